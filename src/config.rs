@@ -0,0 +1,65 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::path::Path;
+use toml::value::{Table, Value};
+
+/// The name of the configuration file looked for in the root of the repository.
+pub const CONFIG_FILE_NAME: &str = ".offstage.toml";
+
+/// Commands to run one after another against the staged files matching a glob
+/// pattern.
+#[derive(Debug)]
+pub struct Entry {
+    pub pattern: String,
+    pub commands: Vec<String>,
+}
+
+/// Reads a configuration file which maps glob patterns to a command or to a list
+/// of commands, such as
+///
+/// ```toml
+/// "*.rs" = ["cargo fmt --", "cargo clippy"]
+/// "*.py" = "black"
+/// ```
+///
+/// The entries are returned in the order they are written in the file.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Entry>> {
+    let path = path.as_ref();
+
+    let inner = || -> Result<Vec<Entry>> {
+        let contents = fs::read_to_string(path)?;
+        let table: Table = toml::from_str(&contents)?;
+
+        table
+            .into_iter()
+            .map(|(pattern, value)| {
+                let commands = match value {
+                    Value::String(command) => Some(vec![command]),
+                    Value::Array(values) => values
+                        .into_iter()
+                        .map(|value| match value {
+                            Value::String(command) => Some(command),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => None,
+                };
+
+                match commands {
+                    Some(commands) => Ok(Entry { pattern, commands }),
+                    None => Err(anyhow!(
+                        "The command for {} must be a string or a list of strings.",
+                        pattern
+                    )),
+                }
+            })
+            .collect()
+    };
+
+    inner().with_context(|| {
+        format!(
+            "Encountered an error when reading the configuration file {}.",
+            path.display()
+        )
+    })
+}
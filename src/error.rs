@@ -0,0 +1,210 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Errors which happened one after another, such as a command failing and then
+/// the snapshot failing to be restored, all of which need to be shown.
+#[derive(Debug)]
+pub struct MultipleErrors(Vec<anyhow::Error>);
+
+impl MultipleErrors {
+    /// Keeps the errors among the results, returning a single error unchanged
+    /// and wrapping several errors together.
+    pub(crate) fn combine<I: IntoIterator<Item = anyhow::Result<()>>>(
+        results: I,
+    ) -> anyhow::Result<()> {
+        let mut errors = results
+            .into_iter()
+            .filter_map(Result::err)
+            .collect::<Vec<_>>();
+
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(MultipleErrors(errors).into()),
+        }
+    }
+
+    /// The errors in the order they happened.
+    pub fn errors(&self) -> &[anyhow::Error] {
+        &self.0
+    }
+}
+
+impl Display for MultipleErrors {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Encountered {} errors.", self.0.len())?;
+
+        for (number, error) in self.0.iter().enumerate() {
+            // The alternate format includes the causes of each error.
+            write!(f, "\n\n{}. {:#}", number + 1, error)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Error for MultipleErrors {}
+
+/// A command exited unsuccessfully, whose exit code offstage exits with too.
+#[derive(Debug)]
+pub struct CommandError {
+    command_line: String,
+    code: Option<i32>,
+}
+
+impl CommandError {
+    pub(crate) fn new(command_line: String, code: Option<i32>) -> Self {
+        Self { command_line, code }
+    }
+
+    /// The exit code of the command, which is absent when it was terminated by
+    /// a signal.
+    pub fn code(&self) -> Option<i32> {
+        self.code
+    }
+
+    /// Finds the failed command behind an error, which is the first one among
+    /// several errors reported together, such as those of commands running at
+    /// the same time or of a restore after the command failed.
+    pub fn find(error: &anyhow::Error) -> Option<&CommandError> {
+        if let Some(command_error) = error.downcast_ref::<CommandError>() {
+            return Some(command_error);
+        }
+
+        error
+            .downcast_ref::<MultipleErrors>()?
+            .errors()
+            .iter()
+            .find_map(CommandError::find)
+    }
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(
+                f,
+                "The command `{}` exited with code {}.",
+                self.command_line, code
+            ),
+            None => write!(
+                f,
+                "The command `{}` was terminated by a signal.",
+                self.command_line
+            ),
+        }
+    }
+}
+
+impl Error for CommandError {}
+
+/// No Git repository was found at or above a directory, as opposed to one being
+/// found but failing to open.
+#[derive(Debug)]
+pub struct RepositoryNotFoundError {
+    path: PathBuf,
+}
+
+impl RepositoryNotFoundError {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl Display for RepositoryNotFoundError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "No Git repository was found in {} or any of its parent directories.",
+            self.path.display()
+        )
+    }
+}
+
+impl Error for RepositoryNotFoundError {}
+
+/// The commands left no changes in the staging area, so committing it would
+/// create an empty commit.
+#[derive(Debug)]
+pub struct EmptyCommitError;
+
+impl Display for EmptyCommitError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Prevented an empty git commit, because the commands left no changes in \
+             the staging area."
+        )
+    }
+}
+
+impl Error for EmptyCommitError {}
+
+/// Offstage was interrupted, such as by Ctrl-C, while running the commands.
+#[derive(Debug)]
+pub struct InterruptedError;
+
+impl Display for InterruptedError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "Interrupted while running the commands.")
+    }
+}
+
+impl Error for InterruptedError {}
+
+/// A command ran for longer than the timeout, so it was killed.
+#[derive(Debug)]
+pub struct TimeoutError {
+    command_line: String,
+    timeout: Duration,
+}
+
+impl TimeoutError {
+    pub(crate) fn new(command_line: String, timeout: Duration) -> Self {
+        Self {
+            command_line,
+            timeout,
+        }
+    }
+}
+
+impl Display for TimeoutError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The command `{}` was killed after running for longer than {} seconds.",
+            self.command_line,
+            self.timeout.as_secs()
+        )
+    }
+}
+
+impl Error for TimeoutError {}
+
+/// A staged file has a path which is not UTF-8, so it cannot be written into a
+/// command line and would otherwise be left out of the command.
+#[derive(Debug)]
+pub struct NonUtf8PathError {
+    path: PathBuf,
+}
+
+impl NonUtf8PathError {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl Display for NonUtf8PathError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "The staged file {} does not have a UTF-8 path, so it cannot be passed as \
+             an argument. Use --stdin or --stdin0 to pass it on standard input instead.",
+            self.path.display()
+        )
+    }
+}
+
+impl Error for NonUtf8PathError {}
@@ -1,16 +1,31 @@
+use super::error::{EmptyCommitError, RepositoryNotFoundError};
 use anyhow::{anyhow, Context, Result};
 use git2::{
-    build::CheckoutBuilder, ApplyLocation, Delta, Diff, DiffFormat, DiffOptions, ErrorCode,
-    IndexAddOption, Oid, Repository, ResetType, Signature, StashApplyOptions, Time,
+    build::CheckoutBuilder, ApplyLocation, ApplyOptions, Delta, Diff, DiffFindOptions, DiffFormat,
+    DiffOptions, ErrorCode, FileMode, IndexAddOption, ObjectType, Oid, Repository, RepositoryState,
+    ResetType, Signature, StashApplyOptions, StashFlags, Time, TreeWalkMode, TreeWalkResult,
 };
 use itertools::Itertools;
+use log::info;
 use std::cell::RefCell;
 use std::collections::HashSet;
+use std::env;
 use std::fs;
 use std::hash::Hash;
 use std::io::ErrorKind::NotFound;
 use std::iter::FromIterator;
 use std::path::{Path, PathBuf};
+use std::process;
+
+/// The start of the message of the stash backing up the working directory, which
+/// tells the backups apart from the user's own stashes. The id of the process
+/// follows it, since the stash list is shared with every worktree and so may hold
+/// the backup of a run that is still going on elsewhere.
+const BACKUP_STASH_MESSAGE: &str = "offstage backup";
+
+/// The message of a backup stash which was kept on purpose, such as after a
+/// failed restore, which is never taken to be left behind.
+const KEPT_STASH_MESSAGE: &str = "offstage kept backup";
 
 /// An abstraction over a Git repository providing complex behavior needed for
 /// applying changes to staged files safely.
@@ -25,6 +40,49 @@ impl GitRepository {
     /// Git repository. Otherwise, this searches up the directory tree from the
     /// current directory to find the repository.
     pub fn open() -> Result<Self> {
+        Self::disable_hash_verification();
+
+        let repository = match Repository::open_from_env() {
+            Ok(repository) => repository,
+            Err(error) if error.code() == ErrorCode::NotFound => {
+                let path = match env::var_os("GIT_DIR") {
+                    Some(git_dir) => PathBuf::from(git_dir),
+                    None => env::current_dir()?,
+                };
+                return Err(RepositoryNotFoundError::new(path).into());
+            }
+            Err(error) => {
+                return Err(
+                    anyhow!(error).context("Encountered an error when opening the Git repository.")
+                )
+            }
+        };
+
+        Ok(Self { repository })
+    }
+
+    /// Attempts to open an already-existing repository by searching up the
+    /// directory tree from the given path.
+    pub fn open_at<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::disable_hash_verification();
+
+        let repository = match Repository::discover(path.as_ref()) {
+            Ok(repository) => repository,
+            Err(error) if error.code() == ErrorCode::NotFound => {
+                return Err(RepositoryNotFoundError::new(path.as_ref().to_path_buf()).into());
+            }
+            Err(error) => {
+                return Err(anyhow!(error).context(format!(
+                    "Encountered an error when opening the Git repository at {}.",
+                    path.as_ref().display()
+                )))
+            }
+        };
+
+        Ok(Self { repository })
+    }
+
+    fn disable_hash_verification() {
         // When strict hash verification is disabled, it means libgit2 will not
         // compute the "object id" of Git objects (which is a SHA-1 hash) after
         // reading them to verify they match the object ids being used to look
@@ -33,57 +91,127 @@ impl GitRepository {
         // one becomes obvious, then we should make this configurable.
         //
         git2::opts::strict_hash_verification(false);
+    }
 
-        let repository = Repository::open_from_env()
-            .with_context(|| "Encountered an error when opening the Git repository.")?;
+    /// Returns the working directory of the repository.
+    pub fn get_working_dir(&self) -> Result<&Path> {
+        self.repository
+            .workdir()
+            .ok_or_else(|| anyhow!("Could not find the working directory of the Git repository."))
+    }
 
-        Ok(Self { repository })
+    /// Returns all the values of a multivalued key in the Git configuration, in
+    /// the order Git reads them, which is none when the key is not set.
+    pub fn get_config_values(&self, name: &str) -> Result<Vec<String>> {
+        let config = self.repository.config()?;
+        let entries = config.multivar(name, None)?;
+
+        let mut values = vec![];
+
+        for entry in &entries {
+            if let Some(value) = entry?.value() {
+                values.push(value.to_string());
+            }
+        }
+
+        Ok(values)
+    }
+
+    /// Returns the directory Git runs hooks from, which is `core.hooksPath` when
+    /// it is set and otherwise the hooks directory inside the git directory.
+    pub fn get_hooks_dir(&self) -> Result<PathBuf> {
+        match self.repository.config()?.get_path("core.hooksPath") {
+            // A relative path is relative to the root of the working directory,
+            // where Git runs the hooks, while joining keeps an absolute path.
+            Ok(hooks_path) => Ok(self.get_working_dir()?.join(hooks_path)),
+            Err(error) if error.code() == ErrorCode::NotFound => {
+                Ok(self.repository.path().join("hooks"))
+            }
+            Err(error) => Err(anyhow!(error)),
+        }
     }
 
-    pub fn save_snapshot(&mut self, staged_files: Vec<PathBuf>) -> Result<Snapshot> {
+    /// Backs up the working directory and, if asked to, hides the unstaged
+    /// changes of the staged files, so that a command run afterward only sees
+    /// staged content.
+    pub fn save_snapshot(
+        &mut self,
+        staged_files: Vec<PathBuf>,
+        hide_partial: bool,
+    ) -> Result<Snapshot> {
         let inner = || -> Result<Snapshot> {
+            self.check_state()?;
+
             let deleted_files = self.get_deleted_files()?;
-            let unstaged_diff = self.save_unstaged_diff()?;
+
+            // Without hiding, there are no unstaged changes to merge back, but the
+            // partially staged files are remembered so that they are only staged
+            // in full when a command changes them.
+            let (unstaged_diff, partially_staged_contents) = if hide_partial {
+                (self.save_unstaged_diff()?, vec![])
+            } else {
+                (None, self.save_partially_staged_contents()?)
+            };
+
             let backup_stash = self.save_snapshot_stash()?;
 
             // Because `git stash` restores the HEAD commit, it brings back uncommitted
             // deleted files. We need to clear them before creating our snapshot.
             GitRepository::delete_files(&deleted_files)?;
 
-            self.hide_partially_staged_changes()?;
+            if hide_partial {
+                self.hide_partially_staged_changes()?;
+            }
 
             Ok(Snapshot {
                 backup_stash,
                 staged_files,
                 unstaged_diff,
+                partially_staged_contents,
             })
         };
 
         inner().with_context(|| "Encountered an error when saving a snapshot.")
     }
 
+    /// Stages the modifications a command made to the staged files of the
+    /// snapshot, and then merges back the unstaged changes hidden from it.
     pub fn apply_modifications(&mut self, snapshot: &Snapshot) -> Result<()> {
         self.stage_modifications(snapshot)?;
 
-        if self.get_staged_files()?.is_empty() {
-            return Err(anyhow!("Prevented an empty git commit."));
-        }
+        let is_empty_commit = self.get_staged_files()?.is_empty();
 
         if let Some(raw_diff) = &snapshot.unstaged_diff {
             let unstaged_diff = Diff::from_buffer(raw_diff)?;
             self.merge_modifications(unstaged_diff)?;
         }
 
+        // This is only reported after the unstaged changes are merged back, so
+        // that the working directory is complete whether or not the caller
+        // treats it as a failure.
+        if is_empty_commit {
+            return Err(EmptyCommitError.into());
+        }
+
         Ok(())
     }
 
+    /// Returns the working directory and the index to how they were when the
+    /// snapshot was saved.
     pub fn restore_snapshot(&mut self, snapshot: &Snapshot) -> Result<()> {
         let mut inner = || -> Result<()> {
             self.hard_reset()?;
+            info!("Reset the working directory and the index to the HEAD commit.");
 
             if let Some(backup_stash) = &snapshot.backup_stash {
+                self.delete_stashed_untracked_files(&backup_stash.stash_id)?;
                 self.apply_stash(&backup_stash.stash_id)?;
+
+                // The merge status is restored before checking the result, so
+                // that it is not lost along with the merge when the check fails.
                 self.restore_merge_status(&backup_stash.merge_status)?;
+                self.verify_stash_applied(&backup_stash.stash_id)?;
+                info!("Applied backup stash {}.", backup_stash.stash_id);
             }
 
             Ok(())
@@ -92,6 +220,7 @@ impl GitRepository {
         inner().with_context(|| "Encountered an error when restoring snapshot after another error.")
     }
 
+    /// Drops the backup of the working directory kept by the snapshot.
     pub fn clean_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
         let inner = || -> Result<()> {
             if let Some(backup_stash) = snapshot.backup_stash {
@@ -105,6 +234,7 @@ impl GitRepository {
                     })?;
 
                 self.repository.stash_drop(stash_index)?;
+                info!("Dropped backup stash {}.", backup_stash.stash_id);
             }
 
             Ok(())
@@ -116,21 +246,138 @@ impl GitRepository {
         })
     }
 
+    /// Keeps the backup of the working directory kept by the snapshot for the
+    /// user to recover from, marking it so that it is not dropped later along
+    /// with the backups left behind.
+    pub fn keep_snapshot(&mut self, snapshot: &Snapshot) -> Result<()> {
+        let mut inner = || -> Result<()> {
+            if let Some(backup_stash) = &snapshot.backup_stash {
+                let stash_id = backup_stash.stash_id;
+
+                // A stash cannot be renamed, so the stash is stored again with
+                // the new message before the old entry is dropped, which then
+                // comes after it, so that it is never missing from the list.
+                self.repository
+                    .stash_store(&stash_id, Some(KEPT_STASH_MESSAGE))?;
+
+                let mut old_index = None;
+
+                self.repository.stash_foreach(|index, message, oid| {
+                    if *oid == stash_id && message != KEPT_STASH_MESSAGE {
+                        old_index = Some(index);
+                        false
+                    } else {
+                        true
+                    }
+                })?;
+
+                if let Some(old_index) = old_index {
+                    self.repository.stash_drop(old_index)?;
+                }
+
+                info!("Marked backup stash {} as kept.", stash_id);
+            }
+
+            Ok(())
+        };
+
+        inner().with_context(|| {
+            "Encountered an error when keeping the backup stash. It might be dropped by \
+             --gc-stashes."
+        })
+    }
+
     fn stage_modifications(&mut self, snapshot: &Snapshot) -> Result<()> {
+        let working_dir = self.get_working_dir()?;
+
+        // Partially staged files which the commands saw with their unstaged
+        // changes keep those changes unstaged unless the commands changed them.
+        let modified_files = snapshot
+            .staged_files
+            .iter()
+            .filter(|path| {
+                snapshot
+                    .partially_staged_contents
+                    .iter()
+                    .find(|(partial_path, _)| partial_path == *path)
+                    .is_none_or(|(_, content_id)| {
+                        Oid::hash_file(ObjectType::Blob, working_dir.join(path)).ok()
+                            != Some(*content_id)
+                    })
+            })
+            .collect_vec();
+
         let mut index = self.repository.index()?;
         index.add_all(
-            &snapshot.staged_files,
+            &modified_files,
             IndexAddOption::DEFAULT | IndexAddOption::DISABLE_PATHSPEC_MATCH,
             None,
         )?;
         index.write()?;
+
+        info!(
+            "Staged the modifications to {} files.",
+            modified_files.len()
+        );
+
         Ok(())
     }
 
     fn merge_modifications(&self, unstaged_diff: Diff) -> Result<()> {
-        self.repository
+        if let Err(error) = self
+            .repository
             .apply(&unstaged_diff, ApplyLocation::WorkDir, None)
-            .with_context(|| "Unstaged changes could not be restored due to a merge conflict.")
+        {
+            let conflicting_files = self.get_conflicting_files(&unstaged_diff)?;
+
+            if conflicting_files.is_empty() {
+                return Err(anyhow!(error)
+                    .context("Unstaged changes could not be restored due to a merge conflict."));
+            }
+
+            return Err(anyhow!(
+                "Unstaged changes could not be restored, because the commands modified \
+                 the lines near the unstaged changes of {}. Stage or stash the unstaged \
+                 changes of these files before trying again.",
+                conflicting_files
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .join(", ")
+            ));
+        }
+
+        info!("Merged the unstaged changes back into the working directory.");
+
+        Ok(())
+    }
+
+    /// Returns the files whose changes in the diff cannot be applied to the
+    /// working directory, by checking each file on its own.
+    fn get_conflicting_files(&self, diff: &Diff) -> Result<Vec<PathBuf>> {
+        let mut conflicting_files = vec![];
+
+        for delta in diff.deltas() {
+            let path = match delta.new_file().path() {
+                Some(path) => path,
+                None => continue,
+            };
+
+            let mut apply_options = ApplyOptions::new();
+            apply_options.check(true);
+            apply_options.delta_callback(|other_delta| {
+                other_delta.and_then(|other_delta| other_delta.new_file().path()) == Some(path)
+            });
+
+            let apply_result =
+                self.repository
+                    .apply(diff, ApplyLocation::WorkDir, Some(&mut apply_options));
+
+            if apply_result.is_err() {
+                conflicting_files.push(path.to_path_buf());
+            }
+        }
+
+        Ok(conflicting_files)
     }
 
     fn hard_reset(&self) -> Result<()> {
@@ -141,6 +388,49 @@ impl GitRepository {
             .map_err(|error| anyhow!(error))
     }
 
+    /// Returns the ids of the backup stashes in the stash list which were left
+    /// behind, such as when offstage was killed before it could drop them.
+    pub fn get_backup_stash_ids(&mut self) -> Result<Vec<Oid>> {
+        Ok(self
+            .get_backup_stashes()?
+            .into_iter()
+            .map(|(_, stash_id)| stash_id)
+            .collect())
+    }
+
+    /// Drops every backup stash in the stash list which was left behind,
+    /// returning their ids.
+    pub fn drop_backup_stashes(&mut self) -> Result<Vec<Oid>> {
+        let backup_stashes = self.get_backup_stashes()?;
+
+        // Dropping a stash shifts the indexes of the ones after it, so they are
+        // dropped from the last one.
+        for (index, stash_id) in backup_stashes.iter().rev() {
+            self.repository.stash_drop(*index)?;
+            info!("Dropped backup stash {}.", stash_id);
+        }
+
+        Ok(backup_stashes
+            .into_iter()
+            .map(|(_, stash_id)| stash_id)
+            .collect())
+    }
+
+    /// Returns the indexes and ids of the backup stashes whose run is over, which
+    /// leaves out those of runs still going on in this or another worktree.
+    fn get_backup_stashes(&mut self) -> Result<Vec<(usize, Oid)>> {
+        let mut backup_stashes = vec![];
+
+        self.repository.stash_foreach(|index, message, stash_id| {
+            if is_left_behind(message) {
+                backup_stashes.push((index, *stash_id));
+            }
+            true
+        })?;
+
+        Ok(backup_stashes)
+    }
+
     fn get_stash_index_from_id(&mut self, stash_id: &Oid) -> Result<Option<usize>> {
         // It would be much better if libgit2 accepted a stash Oid
         // instead of an index from the stash list.
@@ -177,6 +467,84 @@ impl GitRepository {
         Ok(())
     }
 
+    /// Deletes the untracked files which the backup stash holds, because
+    /// applying the stash refuses to overwrite untracked files. Untracked files
+    /// that are not in the stash, such as ignored ones, are left alone.
+    fn delete_stashed_untracked_files(&self, stash_id: &Oid) -> Result<()> {
+        let stash_commit = self.repository.find_commit(*stash_id)?;
+
+        // A stash keeps its untracked files in the tree of a third parent, which
+        // is only there when it has any.
+        if stash_commit.parent_count() < 3 {
+            return Ok(());
+        }
+
+        let working_dir = self.get_working_dir()?;
+        let mut untracked_files = vec![];
+
+        stash_commit
+            .parent(2)?
+            .tree()?
+            .walk(TreeWalkMode::PreOrder, |root, entry| {
+                if entry.kind() == Some(ObjectType::Blob) {
+                    if let Some(name) = entry.name() {
+                        untracked_files.push(working_dir.join(root).join(name));
+                    }
+                }
+                TreeWalkResult::Ok
+            })?;
+
+        // The command may have already deleted some of them.
+        untracked_files.retain(|path| fs::symlink_metadata(path).is_ok());
+
+        GitRepository::delete_files(&untracked_files)
+    }
+
+    /// Checks that the working directory and the index match the backup stash
+    /// after applying it, since an apply which runs into conflicts can leave
+    /// them somewhere in between without failing.
+    fn verify_stash_applied(&self, stash_id: &Oid) -> Result<()> {
+        let stash_commit = self.repository.find_commit(*stash_id)?;
+
+        // The tree of a stash is the working directory, and its second parent is
+        // the index.
+        let stash_tree = stash_commit.tree()?;
+        let stash_index_tree = stash_commit.parent(1)?.tree()?;
+
+        let has_conflicts = self.repository.index()?.has_conflicts();
+
+        // A stash does not hold the changes inside submodules, so they are not
+        // expected to match it.
+        let working_dir_changes = self
+            .repository
+            .diff_tree_to_workdir(
+                Some(&stash_tree),
+                Some(DiffOptions::new().ignore_submodules(true)),
+            )?
+            .deltas()
+            .len();
+
+        let index_changes = self
+            .repository
+            .diff_tree_to_index(
+                Some(&stash_index_tree),
+                None,
+                Some(DiffOptions::new().ignore_submodules(true)),
+            )?
+            .deltas()
+            .len();
+
+        if has_conflicts || working_dir_changes > 0 || index_changes > 0 {
+            return Err(anyhow!(
+                "The working directory and the index do not match backup stash {} after \
+                 applying it, so the stash was kept to recover from.",
+                stash_id
+            ));
+        }
+
+        Ok(())
+    }
+
     fn save_unstaged_diff(&self) -> Result<Option<Vec<u8>>> {
         let partially_staged_files = self.get_partially_staged_files(true)?;
 
@@ -213,6 +581,10 @@ impl GitRepository {
             diff.print(DiffFormat::Patch, |_, _, line| {
                 let origin = line.origin();
 
+                // Only the lines of a hunk need their origin put back in front of
+                // them. Other lines, such as the file headers with their `old mode`
+                // and `new mode` lines, are copied as they are, so that applying
+                // the diff brings back changes to the mode of a file too.
                 if origin == '+' || origin == '-' || origin == ' ' {
                     buffer.push(origin as u8);
                 }
@@ -223,9 +595,33 @@ impl GitRepository {
             Ok(buffer)
         }
 
+        info!(
+            "Saved the unstaged changes of {} partially staged files.",
+            partially_staged_files.len()
+        );
+
         Ok(Some(copy_diff(&unstaged_diff)?))
     }
 
+    /// Returns the ids of the contents in the working directory of the partially
+    /// staged files, which tell whether a command changed them.
+    fn save_partially_staged_contents(&self) -> Result<Vec<(PathBuf, Oid)>> {
+        let working_dir = self.get_working_dir()?;
+
+        // A symbolic link is left out, since hashing it would read its target.
+        self.get_partially_staged_files(false)?
+            .into_iter()
+            .filter(|path| {
+                fs::symlink_metadata(working_dir.join(path))
+                    .is_ok_and(|metadata| metadata.is_file())
+            })
+            .map(|path| -> Result<(PathBuf, Oid)> {
+                let content_id = Oid::hash_file(ObjectType::Blob, working_dir.join(&path))?;
+                Ok((path, content_id))
+            })
+            .collect()
+    }
+
     fn hide_partially_staged_changes(&self) -> Result<()> {
         let partially_staged_files = self.get_partially_staged_files(false)?;
 
@@ -243,34 +639,136 @@ impl GitRepository {
         self.repository
             .checkout_index(None, Some(&mut checkout_options))?;
 
+        info!(
+            "Hid the unstaged changes of {} partially staged files.",
+            partially_staged_files.len()
+        );
+
         Ok(())
     }
 
+    /// Returns the files with changes in the index relative to the HEAD commit,
+    /// which for a renamed file is its new path.
     pub fn get_staged_files(&self) -> Result<Vec<PathBuf>> {
+        self.get_staged_files_where(|_| true)
+    }
+
+    /// Returns the staged files whose kind of change, such as an addition or a
+    /// deletion, is one of the given ones.
+    pub fn get_staged_files_by_status(&self, statuses: &[Delta]) -> Result<Vec<PathBuf>> {
+        self.get_staged_files_where(|status| statuses.contains(&status))
+    }
+
+    fn get_staged_files_where<F: Fn(Delta) -> bool>(&self, predicate: F) -> Result<Vec<PathBuf>> {
         let head_tree = match self.repository.head() {
             Ok(head) => Ok(Some(head.peel_to_tree()?)),
             Err(error) if error.code() == ErrorCode::UnbornBranch => Ok(None),
             Err(error) => Err(error),
         }?;
 
-        let staged_files = self
-            .repository
-            .diff_tree_to_index(head_tree.as_ref(), None, None)?
+        // A change between a file and a symbolic link is one type change rather
+        // than a deletion and an addition of the same path.
+        let mut diff = self.repository.diff_tree_to_index(
+            head_tree.as_ref(),
+            None,
+            Some(DiffOptions::new().include_typechange(true)),
+        )?;
+
+        // Renames are detected so that only the new path of a renamed file is
+        // passed to the commands and staged again, since the old one is gone.
+        // Copies are detected the way `git diff -C` does, from files which are
+        // changed too, so that they can be told apart from additions.
+        diff.find_similar(Some(DiffFindOptions::new().renames(true).copies(true)))?;
+
+        let staged_files = diff
             .deltas()
-            .flat_map(|delta| {
-                if delta.old_file().path() == delta.new_file().path() {
-                    vec![delta.old_file().path()]
-                } else {
-                    vec![delta.old_file().path(), delta.new_file().path()]
+            .filter(|delta| predicate(delta.status()))
+            .filter_map(|delta| {
+                if delta.status() == Delta::Renamed {
+                    if let (Some(old_path), Some(new_path)) =
+                        (delta.old_file().path(), delta.new_file().path())
+                    {
+                        info!(
+                            "Found {} renamed to {}.",
+                            old_path.display(),
+                            new_path.display()
+                        );
+                    }
                 }
+
+                delta.new_file().path()
             })
-            .filter_map(std::convert::identity)
             .map(Path::to_path_buf)
             .collect();
 
         Ok(staged_files)
     }
 
+    /// Returns whether Git considers the staged content of a file to be binary,
+    /// which is never the case for a file that is not in the index.
+    pub fn is_staged_binary<P: AsRef<Path>>(&self, path: P) -> Result<bool> {
+        let entry = match self.repository.index()?.get_path(path.as_ref(), 0) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+
+        Ok(self.repository.find_blob(entry.id)?.is_binary())
+    }
+
+    /// Returns whether a file is staged as a symbolic link, which is never the
+    /// case for a file that is not in the index.
+    pub fn is_staged_symlink<P: AsRef<Path>>(&self, path: P) -> Result<bool> {
+        let entry = match self.repository.index()?.get_path(path.as_ref(), 0) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+
+        Ok(entry.mode == u32::from(FileMode::Link))
+    }
+
+    /// Returns the files among the given ones which differ between the index and
+    /// the working directory, such as those a command modified or deleted.
+    pub fn get_modified_files<P: AsRef<Path>>(&self, files: &[P]) -> Result<Vec<PathBuf>> {
+        if files.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut diff_options = DiffOptions::new();
+        diff_options.disable_pathspec_match(true);
+        for file in files.iter() {
+            diff_options.pathspec(file.as_ref());
+        }
+
+        let modified_files = self
+            .repository
+            .diff_index_to_workdir(None, Some(&mut diff_options))?
+            .deltas()
+            .filter_map(|delta| delta.old_file().path())
+            .map(Path::to_path_buf)
+            .collect();
+
+        Ok(modified_files)
+    }
+
+    /// Returns the files which differ between the index and the working
+    /// directory, including untracked files that are not ignored.
+    pub fn get_unstaged_files(&self) -> Result<HashSet<PathBuf>> {
+        let mut diff_options = DiffOptions::new();
+        diff_options
+            .include_untracked(true)
+            .recurse_untracked_dirs(true);
+
+        let unstaged_files = self
+            .repository
+            .diff_index_to_workdir(None, Some(&mut diff_options))?
+            .deltas()
+            .filter_map(|delta| delta.new_file().path().or_else(|| delta.old_file().path()))
+            .map(Path::to_path_buf)
+            .collect();
+
+        Ok(unstaged_files)
+    }
+
     fn get_partially_staged_files(&self, include_from_files: bool) -> Result<HashSet<PathBuf>> {
         let staged_files = HashSet::from_iter(self.get_staged_files()?);
 
@@ -332,12 +830,15 @@ impl GitRepository {
 
         let signature = create_signature()?;
 
-        let stash_result = self
-            .repository
-            .stash_create(&signature, None, None);
+        let stash_result =
+            self.repository
+                .stash_create(&signature, None, Some(StashFlags::INCLUDE_UNTRACKED));
 
         if let Ok(stash_id) = stash_result {
-            self.repository.stash_store(&stash_id, Some("offstage backup"))?;
+            let message = format!("{} (pid {})", BACKUP_STASH_MESSAGE, process::id());
+            self.repository
+                .stash_store(&stash_id, Some(message.as_str()))?;
+            info!("Created backup stash {}.", stash_id);
         }
 
         match stash_result {
@@ -351,8 +852,28 @@ impl GitRepository {
         }
     }
 
+    /// Refuses to go on during an operation other than a merge, whose state is
+    /// not saved along with the snapshot and could be lost when restoring it.
+    fn check_state(&self) -> Result<()> {
+        let operation = match self.repository.state() {
+            RepositoryState::Clean | RepositoryState::Merge => return Ok(()),
+            RepositoryState::Revert | RepositoryState::RevertSequence => "revert",
+            RepositoryState::CherryPick | RepositoryState::CherryPickSequence => "cherry-pick",
+            RepositoryState::Bisect => "bisect",
+            RepositoryState::Rebase
+            | RepositoryState::RebaseInteractive
+            | RepositoryState::RebaseMerge => "rebase",
+            RepositoryState::ApplyMailbox | RepositoryState::ApplyMailboxOrRebase => "am",
+        };
+
+        Err(anyhow!(
+            "A {} is in progress, which offstage could disrupt. Finish or abort it first.",
+            operation
+        ))
+    }
+
     fn save_merge_status(&self) -> Result<MergeStatus> {
-        let merge_head_path = &self.repository.path().join("MERGE_HEAD");
+        let merge_head_path = &self.get_state_path("MERGE_HEAD");
         let merge_head = Self::read_file_to_string(merge_head_path).with_context(|| {
             format!(
                 "Encountered an error when saving {}.",
@@ -360,7 +881,7 @@ impl GitRepository {
             )
         })?;
 
-        let merge_mode_path = &self.repository.path().join("MERGE_MODE");
+        let merge_mode_path = &self.get_state_path("MERGE_MODE");
         let merge_mode = Self::read_file_to_string(merge_mode_path).with_context(|| {
             format!(
                 "Encountered an error when saving {}.",
@@ -368,7 +889,7 @@ impl GitRepository {
             )
         })?;
 
-        let merge_msg_path = &self.repository.path().join("MERGE_MSG");
+        let merge_msg_path = &self.get_state_path("MERGE_MSG");
         let merge_msg = Self::read_file_to_string(merge_msg_path).with_context(|| {
             format!(
                 "Encountered an error when saving {}.",
@@ -391,7 +912,7 @@ impl GitRepository {
                 .merge_head
                 .as_ref()
                 .map_or(Ok(()), |merge_head| {
-                    let merge_head_path = &self.repository.path().join("MERGE_HEAD");
+                    let merge_head_path = &self.get_state_path("MERGE_HEAD");
                     fs::write(merge_head_path, merge_head).with_context(|| {
                         format!(
                             "Encountered an error when restoring {}.",
@@ -405,7 +926,7 @@ impl GitRepository {
                 .merge_mode
                 .as_ref()
                 .map_or(Ok(()), |merge_mode| {
-                    let merge_mode_path = &self.repository.path().join("MERGE_MODE");
+                    let merge_mode_path = &self.get_state_path("MERGE_MODE");
                     fs::write(merge_mode_path, merge_mode).with_context(|| {
                         format!(
                             "Encountered an error when restoring {}.",
@@ -416,7 +937,7 @@ impl GitRepository {
 
         let restore_merge_msg_result =
             merge_status.merge_msg.as_ref().map_or(Ok(()), |merge_msg| {
-                let merge_msg_path = &self.repository.path().join("MERGE_MSG");
+                let merge_msg_path = &self.get_state_path("MERGE_MSG");
                 fs::write(merge_msg_path, merge_msg).with_context(|| {
                     format!(
                         "Encountered an error when restoring {}.",
@@ -432,6 +953,14 @@ impl GitRepository {
         Ok(())
     }
 
+    /// Returns the path of a file recording the state of an operation such as a
+    /// merge. In a linked worktree, these files live in the git directory of the
+    /// worktree, which is the path libgit2 gives for the repository, while
+    /// shared refs such as the stash live in the common git directory.
+    fn get_state_path(&self, name: &str) -> PathBuf {
+        self.repository.path().join(name)
+    }
+
     fn read_file_to_string<P: AsRef<Path>>(file: P) -> Result<Option<String>> {
         match fs::read_to_string(file) {
             Ok(contents) => Ok(Some(contents)),
@@ -442,6 +971,7 @@ impl GitRepository {
 
     fn delete_files<P: AsRef<Path>>(files: &Vec<P>) -> Result<()> {
         for file in files.iter() {
+            // This removes a symbolic link itself rather than its target.
             fs::remove_file(file).with_context(|| {
                 format!(
                     "Encountered error when deleting {}.",
@@ -454,11 +984,84 @@ impl GitRepository {
     }
 }
 
+/// Parses letters in the style of `git diff --diff-filter` into the kinds of
+/// changes they stand for.
+pub fn parse_diff_filter(letters: &str) -> Result<Vec<Delta>> {
+    letters
+        .chars()
+        .map(|letter| match letter {
+            'A' => Ok(Delta::Added),
+            'C' => Ok(Delta::Copied),
+            'D' => Ok(Delta::Deleted),
+            'M' => Ok(Delta::Modified),
+            'R' => Ok(Delta::Renamed),
+            'T' => Ok(Delta::Typechange),
+            _ => Err(anyhow!(
+                "The diff filter {} is not one of A, C, D, M, R, or T.",
+                letter
+            )),
+        })
+        .collect()
+}
+
+/// Tells whether a stash is a backup whose run is over, which is the case when
+/// the process it names is gone. A backup without a process id is from a version
+/// of offstage which did not record it, and is taken to be left behind.
+fn is_left_behind(message: &str) -> bool {
+    let rest = match message.strip_prefix(BACKUP_STASH_MESSAGE) {
+        Some(rest) => rest,
+        None => return false,
+    };
+
+    if rest.is_empty() {
+        return true;
+    }
+
+    let pid = rest
+        .strip_prefix(" (pid ")
+        .and_then(|rest| rest.strip_suffix(')'))
+        .and_then(|pid| pid.parse::<u32>().ok());
+
+    match pid {
+        Some(pid) => !is_process_running(pid),
+        None => false,
+    }
+}
+
+#[cfg(unix)]
+fn is_process_running(pid: u32) -> bool {
+    // Sending no signal only checks whether the process exists, which it also
+    // does when it belongs to another user and so may not be signaled.
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_process_running(_pid: u32) -> bool {
+    // Without a way to check, the backup is kept in case its run is still going.
+    true
+}
+
+/// A backup of the working directory taken before running commands, along with
+/// the staged files they run against.
 #[derive(Debug)]
 pub struct Snapshot {
+    /// The staged files which the commands run against.
     pub staged_files: Vec<PathBuf>,
     backup_stash: Option<Stash>,
     unstaged_diff: Option<Vec<u8>>,
+    partially_staged_contents: Vec<(PathBuf, Oid)>,
+}
+
+impl Snapshot {
+    /// Returns the id of the stash which backs up the working directory, if
+    /// there was anything to back up.
+    pub fn get_backup_stash_id(&self) -> Option<&Oid> {
+        self.backup_stash
+            .as_ref()
+            .map(|backup_stash| &backup_stash.stash_id)
+    }
 }
 
 #[derive(Debug)]
@@ -0,0 +1,253 @@
+use super::shell;
+use super::workflow::{FilesInput, Options};
+use anyhow::{anyhow, Context, Result};
+use std::env;
+use std::fs;
+use std::io::ErrorKind::NotFound;
+use std::path::{Path, PathBuf};
+
+/// Name of the hook which Git runs before creating a commit.
+const HOOK_NAME: &str = "pre-commit";
+
+/// Comment marking the line that runs offstage in a hook, which is how an
+/// installed hook is recognized.
+const HOOK_MARKER: &str = "# Installed by offstage.";
+
+/// Installs a pre-commit hook which runs offstage with the same options,
+/// returning the path of the hook.
+///
+/// A hook that offstage installed before has its line replaced, so installing
+/// again is harmless. Another hook is left alone unless forced, in which case
+/// the line is appended to it.
+pub fn install_hook(options: &Options, force: bool) -> Result<PathBuf> {
+    let repository = options.open_repository()?;
+
+    let hooks_dir = repository.get_hooks_dir()?;
+    let hook_path = hooks_dir.join(HOOK_NAME);
+
+    let line = build_hook_line(options)?;
+
+    let inner = || -> Result<()> {
+        let contents = match fs::read_to_string(&hook_path) {
+            Ok(contents) => replace_or_append(contents, &line, force, &hook_path)?,
+            Err(error) if error.kind() == NotFound => {
+                format!("#!/bin/sh\n\n{}\n{}\n", HOOK_MARKER, line)
+            }
+            Err(error) => return Err(anyhow!(error)),
+        };
+
+        fs::create_dir_all(&hooks_dir)?;
+        fs::write(&hook_path, contents)?;
+
+        make_executable(&hook_path)
+    };
+
+    inner().with_context(|| {
+        format!(
+            "Encountered an error when installing the hook {}.",
+            hook_path.display()
+        )
+    })?;
+
+    Ok(hook_path)
+}
+
+/// Builds the line which runs offstage from the hook, passing along the options
+/// which tell it what to select and how to run the commands.
+///
+/// Git runs the hook from the root of the working directory of the repository
+/// that it belongs to, so the repository is left out, and the options which
+/// depend on the current directory are either made not to or refused.
+fn build_hook_line(options: &Options) -> Result<String> {
+    if options.cwd_relative {
+        return Err(anyhow!(
+            "The hook cannot select files relative to the current directory, since Git \
+             runs it from the root of the repository. Leave out --cwd-relative."
+        ));
+    }
+
+    if options.dry_run {
+        return Err(anyhow!(
+            "The hook cannot be installed for a dry run. Leave out --dry-run."
+        ));
+    }
+
+    // Dropping backup stashes is left to the user, who may not have recovered
+    // from one yet, rather than done on every commit.
+    if options.gc_stashes {
+        return Err(anyhow!(
+            "The hook cannot drop backup stashes on every commit. Leave out --gc-stashes."
+        ));
+    }
+
+    let defaults = Options::default();
+    let mut words = vec!["offstage".to_string()];
+
+    for filter in options.filters.iter() {
+        words.push(format!("--filter {}", shell::quote(filter)));
+    }
+
+    for exclude in options.excludes.iter() {
+        words.push(format!("--exclude {}", shell::quote(exclude)));
+    }
+
+    if let Some(config) = &options.config {
+        let config = env::current_dir()?.join(config);
+        words.push(format!("--config {}", quote_path(&config)?));
+    }
+
+    for command in options.commands.iter() {
+        words.push(format!("--command {}", shell::quote(command)));
+    }
+
+    if options.per_file {
+        words.push("--per-file".to_string());
+    }
+
+    if options.max_command_length != defaults.max_command_length {
+        words.push(format!(
+            "--max-command-length {}",
+            options.max_command_length
+        ));
+    }
+
+    if options.keep_stash {
+        words.push("--keep-stash".to_string());
+    }
+
+    match options.files_input {
+        FilesInput::Arguments => {}
+        FilesInput::Stdin => words.push("--stdin".to_string()),
+        FilesInput::Stdin0 => words.push("--stdin0".to_string()),
+    }
+
+    if let Some(diff_filter) = &options.diff_filter {
+        words.push(format!("--diff-filter {}", shell::quote(diff_filter)));
+    }
+
+    // The shell is picked from the environment when it is not given, so it only
+    // needs to be passed when the hook would end up with another one.
+    let environment_shell = env::var_os("SHELL").map_or(defaults.shell, PathBuf::from);
+    if options.shell != environment_shell {
+        words.push(format!("--shell {}", quote_path(&options.shell)?));
+    }
+
+    if let Some(shell_flag) = &options.shell_flag {
+        words.push(format!("--shell-flag {}", shell::quote(shell_flag)));
+    }
+
+    if options.absolute {
+        words.push("--absolute".to_string());
+    }
+
+    if options.json {
+        words.push("--json".to_string());
+    }
+
+    if options.text_only {
+        words.push("--text-only".to_string());
+    }
+
+    if options.skip_symlinks {
+        words.push("--skip-symlinks".to_string());
+    }
+
+    if options.ignore_case {
+        words.push("--ignore-case".to_string());
+    }
+
+    if let Some(timeout) = options.timeout {
+        words.push(format!("--timeout {}", timeout.as_secs()));
+    }
+
+    if options.progress {
+        words.push("--progress".to_string());
+    }
+
+    if options.no_sort {
+        words.push("--no-sort".to_string());
+    }
+
+    if options.no_hide_partial {
+        words.push("--no-hide-partial".to_string());
+    }
+
+    if options.jobs != defaults.jobs {
+        words.push(format!("--jobs {}", options.jobs));
+    }
+
+    if options.amend_index {
+        words.push("--amend-index".to_string());
+    }
+
+    // The hook has to stop the commit when offstage fails, even if there is
+    // more in the hook after this line.
+    Ok(format!("{} || exit $?", words.join(" ")))
+}
+
+/// Quotes a path for the line in the hook, which is a shell script and so
+/// cannot hold a path which is not valid UTF-8 as written.
+fn quote_path(path: &Path) -> Result<String> {
+    let path = path.to_str().ok_or_else(|| {
+        anyhow!(
+            "The path {} is not valid UTF-8, so it cannot be written to the hook.",
+            path.display()
+        )
+    })?;
+
+    Ok(shell::quote(path))
+}
+
+/// Replaces the line after the marker in a hook that offstage installed, or
+/// appends the marker and the line to another hook when forced.
+fn replace_or_append(
+    contents: String,
+    line: &str,
+    force: bool,
+    hook_path: &Path,
+) -> Result<String> {
+    let mut lines = contents.lines().map(str::to_string).collect::<Vec<_>>();
+
+    if let Some(index) = lines.iter().position(|existing| existing == HOOK_MARKER) {
+        if index + 1 < lines.len() {
+            lines[index + 1] = line.to_string();
+        } else {
+            lines.push(line.to_string());
+        }
+
+        return Ok(format!("{}\n", lines.join("\n")));
+    }
+
+    if !force {
+        return Err(anyhow!(
+            "A {} hook which offstage did not install already exists at {}. Pass --force \
+             to add offstage to the end of it.",
+            HOOK_NAME,
+            hook_path.display()
+        ));
+    }
+
+    Ok(format!(
+        "{}\n\n{}\n{}\n",
+        contents.trim_end(),
+        HOOK_MARKER,
+        line
+    ))
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_mode(permissions.mode() | 0o111);
+    fs::set_permissions(path, permissions)?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> Result<()> {
+    // Git for Windows runs hooks without looking at permissions.
+    Ok(())
+}
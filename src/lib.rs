@@ -0,0 +1,33 @@
+//! Runs commands against staged Git files.
+//!
+//! The staged files are selected from the repository in the current directory,
+//! the working directory is backed up in a snapshot, and the commands are run
+//! against the selected files with only their staged changes visible. Files the
+//! commands modify are staged again, after which the unstaged changes are merged
+//! back. If anything fails, the working directory is restored from the snapshot.
+//!
+//! [`run`] performs all of this given [`Options`], [`list_files`] only selects
+//! the staged files, and [`install_hook`] sets up a pre-commit hook, while
+//! [`GitRepository`] and [`Snapshot`] expose the individual steps. A command that
+//! fails is reported as a [`CommandError`] carrying its exit code, and a missing
+//! repository as a [`RepositoryNotFoundError`]. The other errors which callers
+//! may want to tell apart are exported too, such as a [`TimeoutError`], and
+//! several errors in a row come wrapped in [`MultipleErrors`].
+//!
+//! [`Options::default`] runs the way the command line does without any flags,
+//! so only the options that matter need to be set.
+
+mod config;
+mod error;
+mod git;
+mod hook;
+mod shell;
+mod workflow;
+
+pub use error::{
+    CommandError, EmptyCommitError, InterruptedError, MultipleErrors, NonUtf8PathError,
+    RepositoryNotFoundError, TimeoutError,
+};
+pub use git::{GitRepository, Snapshot};
+pub use hook::install_hook;
+pub use workflow::{list_files, run, FilesInput, Options};
@@ -1,27 +1,287 @@
 use anyhow::Result;
+use log::LevelFilter;
+use offstage::{CommandError, FilesInput, Options, RepositoryNotFoundError};
 use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
 
-mod git;
-mod workflow;
+/// Exit code when no Git repository is found, which is the one Git uses.
+const REPOSITORY_NOT_FOUND_EXIT_CODE: i32 = 128;
 
 #[derive(Debug, StructOpt)]
 #[structopt(setting = structopt::clap::AppSettings::TrailingVarArg)]
 struct Args {
-    /// Glob pattern to filter staged files
-    #[structopt(long, short)]
-    filter: Option<String>,
+    /// Glob pattern to filter staged files, which may be given more than once
+    #[structopt(long, short, number_of_values = 1)]
+    filter: Vec<String>,
+
+    /// Glob pattern to exclude staged files, even those matching a filter
+    #[structopt(long, short, number_of_values = 1)]
+    exclude: Vec<String>,
+
+    /// Configuration file mapping glob patterns to commands, used when no
+    /// command is given [default: .offstage.toml in the repository root]
+    #[structopt(long)]
+    config: Option<PathBuf>,
+
+    /// Command to run on staged files before the trailing command, which may be
+    /// given more than once to run several commands in order
+    #[structopt(long = "command", number_of_values = 1)]
+    commands: Vec<String>,
+
+    /// Print the selected staged files and the commands to run without running
+    /// them
+    #[structopt(long)]
+    dry_run: bool,
+
+    /// Run the command once per staged file instead of once for all of them
+    #[structopt(long)]
+    per_file: bool,
+
+    /// Maximum length of the command line together with the OFFSTAGE_FILES
+    /// variable, beyond which the staged files are split into batches that each
+    /// run the command [default: 8191 on Windows, 65536 elsewhere]
+    #[structopt(long)]
+    max_command_length: Option<usize>,
 
-    /// Shell executable to use to run the command
+    /// Keep the backup stash when a command fails instead of dropping it after
+    /// restoring from it
+    #[structopt(long)]
+    keep_stash: bool,
+
+    /// Pass the staged files to the command on standard input, one per line,
+    /// instead of as arguments
+    #[structopt(long, conflicts_with = "stdin0")]
+    stdin: bool,
+
+    /// Pass the staged files to the command on standard input, each followed by
+    /// a NUL character, instead of as arguments
+    #[structopt(long)]
+    stdin0: bool,
+
+    /// Letters for the kinds of staged changes to select, as in `git diff
+    /// --diff-filter`: (A)dded, (C)opied from a file which changed too,
+    /// (D)eleted, (M)odified, (R)enamed, or (T)ype changed between a file and a
+    /// symbolic link
+    #[structopt(long)]
+    diff_filter: Option<String>,
+
+    /// Shell executable to use to run the command [default: cmd.exe on Windows,
+    /// sh elsewhere]
     #[structopt(long, short, env = "SHELL")]
-    shell: PathBuf,
+    shell: Option<PathBuf>,
+
+    /// Flag which tells the shell to run a command, such as -c or /C [default:
+    /// detected from the shell]
+    #[structopt(long, allow_hyphen_values = true)]
+    shell_flag: Option<String>,
+
+    /// Pass the staged files to the command as absolute paths instead of paths
+    /// relative to the repository root
+    #[structopt(long)]
+    absolute: bool,
+
+    /// Log each step taken on the repository to standard error
+    #[structopt(long, short)]
+    verbose: bool,
+
+    /// Print a summary of the run as JSON, sending the output of the command to
+    /// standard error instead
+    #[structopt(long, conflicts_with = "dry-run")]
+    json: bool,
+
+    /// Leave out staged files which Git considers binary
+    #[structopt(long)]
+    text_only: bool,
+
+    /// Leave out staged files which are symbolic links, which a command would
+    /// follow to modify their targets instead
+    #[structopt(long)]
+    skip_symlinks: bool,
+
+    /// Path inside the Git repository to run against, whose working directory
+    /// the command then runs in [default: the current directory]
+    #[structopt(long)]
+    repo: Option<PathBuf>,
+
+    /// Match the glob patterns of filters, excludes, and the configuration file
+    /// regardless of case
+    #[structopt(long, short = "i")]
+    ignore_case: bool,
+
+    /// Number of seconds the command may run before it is killed and the
+    /// working directory is restored
+    #[structopt(long)]
+    timeout: Option<u64>,
+
+    /// Show a spinner while the command runs, when standard error is a terminal
+    #[structopt(long)]
+    progress: bool,
+
+    /// Select only staged files inside the current directory, matching filters
+    /// and excludes against their paths relative to it and passing those paths
+    /// to the commands, which run there
+    #[structopt(long)]
+    cwd_relative: bool,
+
+    /// Pass the staged files in the order Git lists them instead of sorted by
+    /// path
+    #[structopt(long)]
+    no_sort: bool,
+
+    /// Run the command against the working directory as it is, without hiding
+    /// unstaged changes, which then get staged in the files the command modifies
+    #[structopt(long)]
+    no_hide_partial: bool,
+
+    /// Number of command groups from the configuration file to run at the same
+    /// time, each against its own staged files, while groups sharing a staged
+    /// file run one after another
+    #[structopt(long, short, default_value = "1")]
+    jobs: usize,
+
+    /// Also stage files which the command changed or created outside the staged
+    /// files, unless they already had unstaged changes, so that all of its
+    /// modifications land in the commit being made
+    #[structopt(long)]
+    amend_index: bool,
 
-    /// Command to run on staged files
+    /// Drop the backup stashes left behind by earlier runs which did not get to
+    /// drop them, instead of warning about them
+    #[structopt(long)]
+    gc_stashes: bool,
+
+    #[structopt(subcommand)]
+    subcommand: Option<Subcommand>,
+
+    /// Command to run on staged files, which follows `--` when it has flags
+    /// that offstage has too or is named like a subcommand
     command: Vec<String>,
 }
 
-fn main() -> Result<()> {
+#[derive(Debug, StructOpt)]
+enum Subcommand {
+    /// Print the staged files which would be selected, one per line, without
+    /// running anything
+    ListFiles,
+
+    /// Install a pre-commit hook which runs offstage with the same options
+    InstallHook {
+        /// Add offstage to the end of an existing pre-commit hook which offstage
+        /// did not install
+        #[structopt(long)]
+        force: bool,
+    },
+}
+
+fn main() {
     let args = Args::from_args();
 
-    workflow::run(&args.shell, &args.command, &args.filter)
+    let mut logger = pretty_env_logger::formatted_builder();
+    if args.verbose {
+        logger.filter_module("offstage", LevelFilter::Info);
+    }
+    logger.init();
+
+    let mut commands = args.commands;
+
+    if !args.command.is_empty() {
+        commands.push(args.command.join(" "));
+    }
+
+    let files_input = if args.stdin {
+        FilesInput::Stdin
+    } else if args.stdin0 {
+        FilesInput::Stdin0
+    } else {
+        FilesInput::Arguments
+    };
+
+    let mut options = Options::default();
+
+    if let Some(shell) = args.shell {
+        options.shell = shell;
+    }
+
+    options.shell_flag = args.shell_flag;
+    options.commands = commands;
+    options.filters = args.filter;
+    options.excludes = args.exclude;
+    options.config = args.config;
+    options.dry_run = args.dry_run;
+    options.per_file = args.per_file;
+    if let Some(max_command_length) = args.max_command_length {
+        options.max_command_length = max_command_length;
+    }
+    options.keep_stash = args.keep_stash;
+    options.files_input = files_input;
+    options.diff_filter = args.diff_filter;
+    options.absolute = args.absolute;
+    options.json = args.json;
+    options.text_only = args.text_only;
+    options.skip_symlinks = args.skip_symlinks;
+    options.repo = args.repo;
+    options.ignore_case = args.ignore_case;
+    options.timeout = args.timeout.map(Duration::from_secs);
+    options.progress = args.progress;
+    options.cwd_relative = args.cwd_relative;
+    options.no_sort = args.no_sort;
+    options.no_hide_partial = args.no_hide_partial;
+    options.jobs = args.jobs;
+    options.amend_index = args.amend_index;
+    options.gc_stashes = args.gc_stashes;
+
+    let result = match args.subcommand {
+        Some(Subcommand::ListFiles) => list_files(&options),
+        Some(Subcommand::InstallHook { force }) => install_hook(&options, force),
+        None => handle_interrupts(&options).and_then(|()| offstage::run(&options)),
+    };
+
+    if let Err(error) = result {
+        // Not being in a repository is reported without the causes, and with
+        // the same exit code as Git, so that scripts can tell it apart.
+        if let Some(not_found_error) = error.downcast_ref::<RepositoryNotFoundError>() {
+            eprintln!("Error: {}", not_found_error);
+            process::exit(REPOSITORY_NOT_FOUND_EXIT_CODE);
+        }
+
+        eprintln!("Error: {:?}", error);
+
+        // A failed command determines the exit code, so that scripts can tell
+        // its failures apart, while any other error exits with a generic code.
+        let code = CommandError::find(&error)
+            .and_then(CommandError::code)
+            .unwrap_or(1);
+
+        process::exit(code);
+    }
+}
+
+fn list_files(options: &Options) -> Result<()> {
+    for path in offstage::list_files(options)? {
+        println!("{}", path.display());
+    }
+
+    Ok(())
+}
+
+fn install_hook(options: &Options, force: bool) -> Result<()> {
+    let hook_path = offstage::install_hook(options, force)?;
+
+    eprintln!("Installed the hook {}.", hook_path.display());
+
+    Ok(())
+}
+
+/// Installs a handler for Ctrl-C which, instead of exiting immediately, raises
+/// the flag for the running command to be stopped and the snapshot to be
+/// restored.
+fn handle_interrupts(options: &Options) -> Result<()> {
+    let interrupted = Arc::clone(&options.interrupted);
+    ctrlc::set_handler(move || interrupted.store(true, Ordering::SeqCst))?;
+
+    Ok(())
 }
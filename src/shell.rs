@@ -0,0 +1,213 @@
+use std::ffi::OsStr;
+use std::path::Path;
+
+/// A shell which runs the lines built for commands against the staged files.
+pub struct Shell<'a> {
+    program: &'a Path,
+    flag: &'a str,
+    kind: ShellKind,
+}
+
+impl<'a> Shell<'a> {
+    /// Describes the shell, detecting its family from the name of the program
+    /// to know how to tell it to run a line, unless the flag for it is given.
+    pub fn new(program: &'a Path, flag: Option<&'a str>) -> Self {
+        let kind = ShellKind::detect(program);
+
+        Self {
+            program,
+            flag: flag.unwrap_or_else(|| kind.flag()),
+            kind,
+        }
+    }
+
+    pub fn program(&self) -> &Path {
+        self.program
+    }
+
+    /// Returns the arguments which tell the shell to run the line.
+    pub fn arguments(&self, command_line: String) -> Vec<String> {
+        vec![self.flag.to_string(), command_line]
+    }
+
+    /// Returns the arguments for the command interpreter as the exact text of its
+    /// command line, or nothing for the other shells.
+    ///
+    /// The command interpreter reads the line after its flag as it is, rather
+    /// than splitting it the way other programs do, so it must not go through the
+    /// usual quoting of arguments, which would escape the double quotes around
+    /// the paths with backslashes that it does not understand. `/S` tells it to
+    /// strip the double quotes around the line and nothing else.
+    pub fn raw_arguments(&self, command_line: &str) -> Option<String> {
+        match self.kind {
+            ShellKind::Cmd => Some(format!("/S {} \"{}\"", self.flag, command_line)),
+            ShellKind::Posix | ShellKind::PowerShell => None,
+        }
+    }
+
+    /// Quotes an argument so that the shell passes it to the command as a single
+    /// word, exactly as written.
+    pub fn quote(&self, argument: &str) -> String {
+        match self.kind {
+            ShellKind::Posix => quote(argument),
+            // Paths on Windows cannot contain double quotes, so double quotes
+            // keep spaces from splitting them, but the command interpreter still
+            // expands variables between percent signs inside them. A percent
+            // sign is escaped with a caret outside of the quotes instead, which
+            // also leaves no pair of percent signs around a name that can be set.
+            ShellKind::Cmd => format!("\"{}\"", argument.replace('%', "\"^%\"")),
+            ShellKind::PowerShell => format!("'{}'", argument.replace('\'', "''")),
+        }
+    }
+}
+
+/// The families of shells, which differ in how they are told to run a line and
+/// in how they quote arguments.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ShellKind {
+    Posix,
+    Cmd,
+    PowerShell,
+}
+
+impl ShellKind {
+    fn detect(program: &Path) -> Self {
+        let name = program
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .map(str::to_ascii_lowercase);
+
+        match name.as_deref() {
+            Some("cmd") => ShellKind::Cmd,
+            Some("powershell") | Some("pwsh") => ShellKind::PowerShell,
+            _ => ShellKind::Posix,
+        }
+    }
+
+    fn flag(self) -> &'static str {
+        match self {
+            ShellKind::Posix => "-c",
+            ShellKind::Cmd => "/C",
+            ShellKind::PowerShell => "-Command",
+        }
+    }
+}
+
+/// Quotes an argument so that a POSIX shell passes it to the command as a
+/// single word, exactly as written.
+///
+/// The argument is wrapped in single quotes, inside of which the shell does not
+/// interpret any characters. A single quote in the argument itself cannot be
+/// escaped inside single quotes, so it is written as `'\''`, which closes the
+/// quoted string, adds an escaped quote, and opens a new quoted string.
+pub fn quote(argument: &str) -> String {
+    format!("'{}'", argument.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{quote, Shell};
+    use std::path::Path;
+
+    fn arguments(program: &str, flag: Option<&str>) -> Vec<String> {
+        Shell::new(Path::new(program), flag).arguments("echo".to_string())
+    }
+
+    #[test]
+    fn posix_shell_runs_line_with_c_flag() {
+        assert_eq!(arguments("/bin/bash", None), vec!["-c", "echo"]);
+        assert_eq!(arguments("sh", None), vec!["-c", "echo"]);
+    }
+
+    #[test]
+    fn cmd_runs_line_with_slash_c_flag() {
+        assert_eq!(arguments("cmd.exe", None), vec!["/C", "echo"]);
+        assert_eq!(arguments("CMD.EXE", None), vec!["/C", "echo"]);
+    }
+
+    #[test]
+    fn powershell_runs_line_with_command_flag() {
+        assert_eq!(arguments("powershell.exe", None), vec!["-Command", "echo"]);
+        assert_eq!(arguments("pwsh", None), vec!["-Command", "echo"]);
+    }
+
+    #[test]
+    fn given_flag_overrides_detected_flag() {
+        assert_eq!(arguments("/usr/bin/fish", Some("-e")), vec!["-e", "echo"]);
+    }
+
+    #[test]
+    fn cmd_quotes_argument_with_double_quotes() {
+        let shell = Shell::new(Path::new("cmd.exe"), None);
+        assert_eq!(shell.quote("my notes.md"), r#""my notes.md""#);
+    }
+
+    #[test]
+    fn cmd_escapes_percent_signs_outside_double_quotes() {
+        let shell = Shell::new(Path::new("cmd.exe"), None);
+        assert_eq!(shell.quote("%PATH% notes.md"), r#"""^%"PATH"^%" notes.md""#);
+    }
+
+    #[test]
+    fn cmd_gets_line_as_raw_arguments() {
+        let shell = Shell::new(Path::new("cmd.exe"), None);
+        let command_line = format!("prettier --write {}", shell.quote("my notes.md"));
+
+        assert_eq!(
+            shell.raw_arguments(&command_line).as_deref(),
+            Some(r#"/S /C "prettier --write "my notes.md"""#)
+        );
+    }
+
+    #[test]
+    fn cmd_raw_arguments_keep_given_flag() {
+        let shell = Shell::new(Path::new("cmd"), Some("/D /C"));
+        assert_eq!(
+            shell.raw_arguments("echo").as_deref(),
+            Some(r#"/S /D /C "echo""#)
+        );
+    }
+
+    #[test]
+    fn other_shells_get_no_raw_arguments() {
+        assert_eq!(
+            Shell::new(Path::new("sh"), None).raw_arguments("echo"),
+            None
+        );
+        assert_eq!(
+            Shell::new(Path::new("pwsh"), None).raw_arguments("echo"),
+            None
+        );
+    }
+
+    #[test]
+    fn powershell_quotes_argument_with_single_quotes() {
+        let shell = Shell::new(Path::new("pwsh"), None);
+        assert_eq!(shell.quote("it's.md"), "'it''s.md'");
+    }
+
+    #[test]
+    fn quote_plain_argument() {
+        assert_eq!(quote("src/main.rs"), "'src/main.rs'");
+    }
+
+    #[test]
+    fn quote_argument_with_spaces() {
+        assert_eq!(quote("my notes.md"), "'my notes.md'");
+    }
+
+    #[test]
+    fn quote_argument_with_single_quotes() {
+        assert_eq!(quote("it's.md"), r"'it'\''s.md'");
+    }
+
+    #[test]
+    fn quote_argument_with_double_quotes() {
+        assert_eq!(quote(r#"say "hi".md"#), r#"'say "hi".md'"#);
+    }
+
+    #[test]
+    fn quote_argument_with_expansions() {
+        assert_eq!(quote("$HOME `pwd` *.md"), "'$HOME `pwd` *.md'");
+    }
+}
@@ -1,79 +1,616 @@
-use super::git::{GitRepository, Snapshot};
-use anyhow::Result;
-use duct::cmd;
-use globset::Glob;
+use super::config::{self, CONFIG_FILE_NAME};
+use super::error::{
+    CommandError, EmptyCommitError, InterruptedError, MultipleErrors, NonUtf8PathError,
+    TimeoutError,
+};
+use super::git::{self, GitRepository, Snapshot};
+use super::shell::Shell;
+use anyhow::{anyhow, Result};
+use crossbeam_utils::thread;
+use duct::{cmd, Expression, Handle};
+use globset::{Glob, GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use indicatif::{ProgressBar, ProgressStyle};
 use itertools::Itertools;
-use std::path::Path;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// How often a running command is checked for whether it has finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often the spinner shown while a command runs moves, in milliseconds.
+const PROGRESS_TICK_INTERVAL: u64 = 100;
+
+/// Maximum length of a command line by default. The command interpreter on
+/// Windows takes lines of up to 8191 characters, while the limits of the other
+/// common platforms are well above 65536.
+const DEFAULT_MAX_COMMAND_LENGTH: usize = if cfg!(windows) { 8191 } else { 65536 };
+
+/// Keys in the Git configuration for the commands, filters, and excludes used
+/// when none are given in the options, each of which may be set more than once.
+const COMMAND_CONFIG_KEY: &str = "offstage.command";
+const FILTER_CONFIG_KEY: &str = "offstage.filter";
+const EXCLUDE_CONFIG_KEY: &str = "offstage.exclude";
+
+/// Settings which determine the staged files to select and the commands to run
+/// against them. Start from the default, which matches the command line without
+/// any flags, since more options may be added.
+#[non_exhaustive]
+pub struct Options {
+    /// Shell executable used to run the commands.
+    pub shell: PathBuf,
+    /// Flag which tells the shell to run a command, or the one usual for the
+    /// kind of shell when absent.
+    pub shell_flag: Option<String>,
+    /// Commands to run one after another against all selected staged files.
+    /// When empty, the commands are read from `offstage.command` in the Git
+    /// configuration or else from a configuration file instead.
+    pub commands: Vec<String>,
+    /// Glob patterns of which a staged file must match at least one, unless
+    /// there are none. When empty, they are read from `offstage.filter` in the
+    /// Git configuration instead.
+    pub filters: Vec<String>,
+    /// Glob patterns of which a staged file must match none. When empty, they
+    /// are read from `offstage.exclude` in the Git configuration instead.
+    pub excludes: Vec<String>,
+    /// Configuration file to read the commands from, which defaults to the one
+    /// in the root of the repository.
+    pub config: Option<PathBuf>,
+    /// Whether to only print the selected staged files and the commands that
+    /// would run, without touching the repository or running anything.
+    pub dry_run: bool,
+    /// Whether to run each command once per staged file instead of once
+    /// against all of them.
+    pub per_file: bool,
+    /// Maximum length of the line the shell runs for a command together with the
+    /// variable listing its files, beyond which the staged files are split into
+    /// batches that each run the command.
+    pub max_command_length: usize,
+    /// Whether to keep the backup stash instead of dropping it when a command
+    /// fails, as an extra safety net on top of restoring the snapshot.
+    pub keep_stash: bool,
+    /// How the staged files are passed to the commands.
+    pub files_input: FilesInput,
+    /// Letters in the style of `git diff --diff-filter` for the kinds of
+    /// staged changes to select, or all of them when absent.
+    pub diff_filter: Option<String>,
+    /// Whether to pass the staged files to the commands as absolute paths
+    /// instead of paths relative to the root of the repository.
+    pub absolute: bool,
+    /// Whether to print a summary of the run as JSON on standard output, to
+    /// which end the output of the commands goes to standard error instead. This
+    /// is ignored for a dry run.
+    pub json: bool,
+    /// Whether to leave out the staged files which Git considers binary.
+    pub text_only: bool,
+    /// Whether to leave out the staged files which are symbolic links, which
+    /// the commands would follow to modify their targets instead. Otherwise the
+    /// links are passed like any other file, and stay links.
+    pub skip_symlinks: bool,
+    /// Path inside the repository to run against, which defaults to the one
+    /// found from the current directory. The commands run in the working
+    /// directory of the repository unless asked to run in the current one.
+    pub repo: Option<PathBuf>,
+    /// Whether glob patterns match staged files regardless of case.
+    pub ignore_case: bool,
+    /// How long each command may run before it is killed, along with the
+    /// processes it started, and the snapshot is restored, or without a limit
+    /// when absent. With a limit, the commands run in a process group of their
+    /// own on Unix, which cannot read from the terminal.
+    pub timeout: Option<Duration>,
+    /// Whether to show a spinner on standard error while each command runs,
+    /// which only happens when standard error is a terminal.
+    pub progress: bool,
+    /// Whether to only select staged files inside the current directory, and to
+    /// match filters and excludes against their paths relative to it. The
+    /// commands then run in the current directory, even with a repository given,
+    /// and are passed the paths relative to it.
+    pub cwd_relative: bool,
+    /// Whether to pass the staged files in the order Git lists them instead of
+    /// sorted by path.
+    pub no_sort: bool,
+    /// Whether to run the commands against the working directory as it is,
+    /// instead of hiding the unstaged changes of partially staged files. Those
+    /// changes are then staged along with the modifications of the commands to
+    /// the files, and stay unstaged in the files the commands leave alone.
+    pub no_hide_partial: bool,
+    /// How many groups of commands from a configuration file may run at the
+    /// same time, each against its own staged files. Groups which share a staged
+    /// file still run one after another. Their output is held back until each
+    /// command finishes, and no spinner is shown.
+    pub jobs: usize,
+    /// Whether to also stage files outside the staged files which the commands
+    /// changed or created, so that all of their modifications land in the commit
+    /// being made, as a pre-commit hook wants. Files which already had unstaged
+    /// changes are left alone, since staging them would take those along.
+    pub amend_index: bool,
+    /// Whether to drop the backup stashes left behind by earlier runs, rather
+    /// than only warning about them.
+    pub gc_stashes: bool,
+    /// Flag which, once raised, such as by a handler for Ctrl-C, stops the
+    /// running command and restores the snapshot. Offstage does not install a
+    /// handler of its own, so that a program embedding it keeps control of its
+    /// signals.
+    pub interrupted: Arc<AtomicBool>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            shell: default_shell(),
+            shell_flag: None,
+            commands: vec![],
+            filters: vec![],
+            excludes: vec![],
+            config: None,
+            dry_run: false,
+            per_file: false,
+            max_command_length: DEFAULT_MAX_COMMAND_LENGTH,
+            keep_stash: false,
+            files_input: FilesInput::Arguments,
+            diff_filter: None,
+            absolute: false,
+            json: false,
+            text_only: false,
+            skip_symlinks: false,
+            repo: None,
+            ignore_case: false,
+            timeout: None,
+            progress: false,
+            cwd_relative: false,
+            no_sort: false,
+            no_hide_partial: false,
+            jobs: 1,
+            amend_index: false,
+            gc_stashes: false,
+            interrupted: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+impl Options {
+    fn get_shell(&self) -> Shell {
+        Shell::new(&self.shell, self.shell_flag.as_deref())
+    }
+
+    pub(crate) fn open_repository(&self) -> Result<GitRepository> {
+        match &self.repo {
+            Some(path) => GitRepository::open_at(path),
+            None => GitRepository::open(),
+        }
+    }
+}
+
+/// Picks the shell to use when none is given, which on Windows is the command
+/// interpreter.
+fn default_shell() -> PathBuf {
+    if cfg!(windows) {
+        env::var_os("COMSPEC").map_or_else(|| PathBuf::from("cmd.exe"), PathBuf::from)
+    } else {
+        PathBuf::from("sh")
+    }
+}
+
+/// The ways in which staged files can be passed to a command.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilesInput {
+    /// As arguments, in place of the placeholders or at the end of the command.
+    Arguments,
+    /// On standard input, each path followed by a newline.
+    Stdin,
+    /// On standard input, each path followed by a NUL character.
+    Stdin0,
+}
 
 /// Runs the core logic to back up the working directory, apply a command to the
 /// staged files, and handle errors.
-pub fn run<P: AsRef<Path>>(shell: P, command: &Vec<String>, filter: &Option<String>) -> Result<()> {
-    if let Some(mut workflow) = Workflow::prepare(filter)? {
-        let result = workflow.run(shell, command);
+pub fn run(options: &Options) -> Result<()> {
+    if options.dry_run {
+        return preview(options);
+    }
+
+    let mut summary = Summary::default();
+
+    let result = run_workflow(options, &mut summary);
+
+    // Failing to print the summary must not hide how the run went, such as the
+    // exit code of a failed command.
+    let print_result = if options.json {
+        serde_json::to_string(&summary)
+            .map(|summary| println!("{}", summary))
+            .map_err(|error| {
+                anyhow!(error).context("Encountered an error when printing the summary.")
+            })
+    } else {
+        Ok(())
+    };
+
+    MultipleErrors::combine(vec![result, print_result])
+}
+
+/// What happened during a run, which is printed as JSON for other tools.
+#[derive(Default, Serialize)]
+struct Summary {
+    /// The staged files which the commands ran against, whose paths are made
+    /// valid UTF-8 for JSON by replacing what is not.
+    staged_files: Vec<String>,
+    /// Whether any staged files were selected for the commands to run.
+    command_ran: bool,
+    /// The exit code of the command that failed, or zero when all succeeded,
+    /// which is absent when the failure has no exit code.
+    exit_code: Option<i32>,
+    /// Whether the working directory was restored from the snapshot.
+    restored: bool,
+    /// The backup stash kept after a failure.
+    backup_stash: Option<String>,
+}
+
+fn run_workflow(options: &Options, summary: &mut Summary) -> Result<()> {
+    if let Some(mut workflow) = Workflow::prepare(options)? {
+        summary.staged_files = workflow
+            .snapshot
+            .staged_files
+            .iter()
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
+        summary.command_ran = true;
 
-        // TODO: We need to aggregate these errors and show all of them.
+        let mut result = workflow.run(options);
 
-        // TODO: We need to show a message when a commit was prevented because it
-        // would be an empty commit.
+        let is_empty_commit = result
+            .as_ref()
+            .err()
+            .is_some_and(|error| error.downcast_ref::<EmptyCommitError>().is_some());
 
-        if result.is_err() {
-            workflow.restore()?;
+        // The commands succeeded but undid every staged change, which is not a
+        // failure, so the working directory is left the way they left it.
+        if is_empty_commit {
+            eprintln!("{}", EmptyCommitError);
+            result = Ok(());
         }
 
-        workflow.cleanup()?;
+        summary.exit_code = match &result {
+            Ok(()) => Some(0),
+            Err(error) => CommandError::find(error).and_then(CommandError::code),
+        };
+
+        let restore_result = if result.is_err() {
+            summary.restored = true;
+            workflow.restore()
+        } else {
+            Ok(())
+        };
+
+        // The backup stash is never dropped when restoring from it failed, since
+        // it may then be the only copy of the working directory.
+        let cleanup_result = if (result.is_err() && options.keep_stash) || restore_result.is_err() {
+            summary.backup_stash = workflow
+                .snapshot
+                .get_backup_stash_id()
+                .map(ToString::to_string);
+            workflow.keep_backup()
+        } else {
+            workflow.cleanup()
+        };
 
-        return result;
+        return MultipleErrors::combine(vec![result, restore_result, cleanup_result]);
     }
 
     Ok(())
 }
 
+/// Selects the staged files which the commands would run against, without
+/// touching the repository or running anything. When there are neither commands
+/// nor a configuration file, only the filters narrow down the staged files.
+pub fn list_files(options: &Options) -> Result<Vec<PathBuf>> {
+    let repository = options.open_repository()?;
+
+    let tasks = if options.commands.is_empty()
+        && Workflow::find_config_path(&repository, options)?.is_none()
+    {
+        vec![Task {
+            commands: vec![],
+            matcher: None,
+        }]
+    } else {
+        Workflow::load_tasks(&repository, options)?
+    };
+
+    Workflow::select_staged_files(&repository, options, &tasks)
+}
+
 struct Workflow {
     repository: GitRepository,
     snapshot: Snapshot,
+    tasks: Vec<Task>,
+    unstaged_files: Option<HashSet<PathBuf>>,
 }
 
 impl Workflow {
-    fn prepare(filter: &Option<String>) -> Result<Option<Self>> {
-        let mut repository = GitRepository::open()?;
+    fn prepare(options: &Options) -> Result<Option<Self>> {
+        let mut repository = options.open_repository()?;
 
-        let mut staged_files = repository.get_staged_files()?;
+        Self::check_backup_stashes(&mut repository, options)?;
 
-        if let Some(filter) = filter {
-            let glob_matcher = Glob::new(filter)?.compile_matcher();
-            staged_files = staged_files
-                .into_iter()
-                .filter(|path| glob_matcher.is_match(path))
-                .collect();
-        }
+        let tasks = Self::load_tasks(&repository, options)?;
+
+        let staged_files = Self::select_staged_files(&repository, options, &tasks)?;
 
         if staged_files.is_empty() {
             return Ok(None);
         }
 
-        let snapshot = repository.save_snapshot(staged_files)?;
+        let snapshot = repository.save_snapshot(staged_files, !options.no_hide_partial)?;
+
+        // These are the files the commands see unstaged changes in, which are
+        // not theirs to stage.
+        let unstaged_files = if options.amend_index {
+            Some(repository.get_unstaged_files()?)
+        } else {
+            None
+        };
 
         Ok(Some(Self {
             repository,
             snapshot,
+            tasks,
+            unstaged_files,
         }))
     }
 
-    fn run<P: AsRef<Path>>(&mut self, shell: P, command: &Vec<String>) -> Result<()> {
-        let staged_files_iter = self
-            .snapshot
-            .staged_files
-            .iter()
-            .filter_map(|path| path.to_str());
+    /// Warns about backup stashes which an earlier run did not get to drop, such
+    /// as when it was killed, or drops them if asked to.
+    fn check_backup_stashes(repository: &mut GitRepository, options: &Options) -> Result<()> {
+        if options.gc_stashes {
+            let stash_ids = repository.drop_backup_stashes()?;
+
+            if !stash_ids.is_empty() {
+                eprintln!(
+                    "Dropped {} backup stashes left behind by an earlier run: {}.",
+                    stash_ids.len(),
+                    stash_ids.iter().join(", ")
+                );
+            }
+
+            return Ok(());
+        }
+
+        let stash_ids = repository.get_backup_stash_ids()?;
+
+        if !stash_ids.is_empty() {
+            eprintln!(
+                "Found {} backup stashes left behind by an earlier run: {}. Recover from \
+                 one with `git stash apply <id>`, or drop them all with --gc-stashes.",
+                stash_ids.len(),
+                stash_ids.iter().join(", ")
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Determines the commands to run, which are the ones given on the command
+    /// line, or else the ones in a configuration file given on the command line,
+    /// or else the ones in the Git configuration, or else the ones in the
+    /// configuration file of the repository.
+    fn load_tasks(repository: &GitRepository, options: &Options) -> Result<Vec<Task>> {
+        if !options.commands.is_empty() {
+            return Ok(vec![Task {
+                commands: options.commands.clone(),
+                matcher: None,
+            }]);
+        }
+
+        if options.config.is_none() {
+            let commands = repository.get_config_values(COMMAND_CONFIG_KEY)?;
+
+            if !commands.is_empty() {
+                return Ok(vec![Task {
+                    commands,
+                    matcher: None,
+                }]);
+            }
+        }
+
+        let config_path = Self::find_config_path(repository, options)?.ok_or_else(|| {
+            anyhow!(
+                "No command was given and no {} was found in the repository.",
+                CONFIG_FILE_NAME
+            )
+        })?;
+
+        config::load(&config_path)?
+            .into_iter()
+            .map(|entry| -> Result<Task> {
+                Ok(Task {
+                    commands: entry.commands,
+                    matcher: Some(build_glob(&entry.pattern, options)?.compile_matcher()),
+                })
+            })
+            .collect()
+    }
+
+    /// Finds the configuration file to read the commands from, which is absent
+    /// when none was given and the repository does not have one.
+    fn find_config_path(repository: &GitRepository, options: &Options) -> Result<Option<PathBuf>> {
+        if let Some(config_path) = &options.config {
+            return Ok(Some(config_path.clone()));
+        }
+
+        let config_path = repository.get_working_dir()?.join(CONFIG_FILE_NAME);
+
+        Ok(if config_path.is_file() {
+            Some(config_path)
+        } else {
+            None
+        })
+    }
+
+    /// Returns the current directory relative to the root of the repository.
+    fn get_current_dir(repository: &GitRepository) -> Result<PathBuf> {
+        let working_dir = fs::canonicalize(repository.get_working_dir()?)?;
+        let current_dir = fs::canonicalize(env::current_dir()?)?;
+
+        let relative_dir = current_dir.strip_prefix(&working_dir).map_err(|_| {
+            anyhow!(
+                "The current directory {} is not inside the repository.",
+                current_dir.display()
+            )
+        })?;
+
+        Ok(relative_dir.to_path_buf())
+    }
+
+    /// Returns the directory inside the repository which the paths of the staged
+    /// files are relative to, for the filters and for the commands, which is the
+    /// current directory when asked for and otherwise the root.
+    fn get_files_dir(repository: &GitRepository, options: &Options) -> Result<PathBuf> {
+        if options.cwd_relative {
+            Self::get_current_dir(repository)
+        } else {
+            Ok(PathBuf::new())
+        }
+    }
+
+    /// Determines the staged files which pass the filters and which at least one
+    /// of the tasks runs against.
+    fn select_staged_files(
+        repository: &GitRepository,
+        options: &Options,
+        tasks: &[Task],
+    ) -> Result<Vec<PathBuf>> {
+        let mut staged_files = match &options.diff_filter {
+            Some(diff_filter) => {
+                repository.get_staged_files_by_status(&git::parse_diff_filter(diff_filter)?)?
+            }
+            None => repository.get_staged_files()?,
+        };
+
+        // Filters match paths relative to this directory inside the repository,
+        // which by default is its root.
+        let filters_dir = Self::get_files_dir(repository, options)?;
+
+        if options.cwd_relative {
+            staged_files.retain(|path| path.starts_with(&filters_dir));
+        }
+
+        // Filters and excludes given on the command line replace the ones in
+        // the Git configuration.
+        let filters = if options.filters.is_empty() {
+            repository.get_config_values(FILTER_CONFIG_KEY)?
+        } else {
+            options.filters.clone()
+        };
+
+        let excludes = if options.excludes.is_empty() {
+            repository.get_config_values(EXCLUDE_CONFIG_KEY)?
+        } else {
+            options.excludes.clone()
+        };
 
-        let command = command
+        if !filters.is_empty() {
+            let glob_set = build_glob_set(&filters, options)?;
+            staged_files
+                .retain(|path| glob_set.is_match(path.strip_prefix(&filters_dir).unwrap_or(path)));
+        }
+
+        if !excludes.is_empty() {
+            let glob_set = build_glob_set(&excludes, options)?;
+            staged_files
+                .retain(|path| !glob_set.is_match(path.strip_prefix(&filters_dir).unwrap_or(path)));
+        }
+
+        let mut selected_files = vec![];
+
+        for path in staged_files {
+            if options.skip_symlinks && repository.is_staged_symlink(&path)? {
+                continue;
+            }
+
+            if options.text_only && repository.is_staged_binary(&path)? {
+                continue;
+            }
+
+            selected_files.push(path);
+        }
+
+        staged_files = selected_files;
+
+        // Staged files which no task runs against are left out of the snapshot
+        // so that they are not staged again afterward.
+        staged_files.retain(|path| tasks.iter().any(|task| task.matches(path)));
+
+        // The order of the diff is not guaranteed, so the files are sorted for
+        // the commands to see them in the same order every time.
+        if !options.no_sort {
+            staged_files.sort();
+        }
+
+        Ok(staged_files)
+    }
+
+    fn run(&mut self, options: &Options) -> Result<()> {
+        let working_dir = self.repository.get_working_dir()?;
+
+        let groups = self
+            .tasks
             .iter()
-            .map(String::as_str)
-            .chain(staged_files_iter)
-            .join(" ");
+            .map(|task| {
+                let task_files = self
+                    .snapshot
+                    .staged_files
+                    .iter()
+                    .filter(|path| task.matches(path))
+                    .collect_vec();
+
+                (task, task_files)
+            })
+            .filter(|(_, task_files)| !task_files.is_empty())
+            .collect_vec();
+
+        if options.jobs <= 1 || groups.len() <= 1 {
+            for (task, task_files) in groups {
+                run_task(
+                    &self.repository,
+                    options,
+                    working_dir,
+                    task,
+                    task_files,
+                    &options.interrupted,
+                )?;
+            }
+        } else {
+            run_tasks_in_parallel(options, working_dir, groups, &options.interrupted)?;
+        }
+
+        if let Some(unstaged_files) = &self.unstaged_files {
+            let changed_files = self
+                .repository
+                .get_unstaged_files()?
+                .into_iter()
+                .filter(|path| {
+                    !unstaged_files.contains(path)
+                        && !self.snapshot.staged_files.contains(path)
+                        && fs::symlink_metadata(working_dir.join(path)).is_ok()
+                })
+                .sorted()
+                .collect_vec();
 
-        cmd!(shell.as_ref(), "-c", command).run()?;
+            self.snapshot.staged_files.extend(changed_files);
+        }
 
+        // The index is only touched here, after all the commands finished, since
+        // libgit2 does not support modifying it from several threads.
         self.repository.apply_modifications(&self.snapshot)
     }
 
@@ -84,4 +621,821 @@ impl Workflow {
     fn cleanup(mut self) -> Result<()> {
         self.repository.clean_snapshot(self.snapshot)
     }
+
+    /// Leaves the backup stash of the snapshot in place, telling the user where
+    /// to find it.
+    fn keep_backup(mut self) -> Result<()> {
+        let result = self.repository.keep_snapshot(&self.snapshot);
+
+        if let Some(stash_id) = self.snapshot.get_backup_stash_id() {
+            eprintln!(
+                "Kept the backup stash {}, which can be found with `git stash list`.",
+                stash_id
+            );
+        }
+
+        result
+    }
+}
+
+/// Runs the commands of a task one after another against its staged files,
+/// stopping at the first one that fails.
+fn run_task(
+    repository: &GitRepository,
+    options: &Options,
+    working_dir: &Path,
+    task: &Task,
+    mut task_files: Vec<&PathBuf>,
+    interrupted: &AtomicBool,
+) -> Result<()> {
+    let files_dir = Workflow::get_files_dir(repository, options)?;
+
+    for command in &task.commands {
+        if task_files.is_empty() {
+            break;
+        }
+
+        let command_files = resolve_paths(options, working_dir, &files_dir, &task_files);
+        let command_files = command_files.iter().collect_vec();
+
+        execute(options, working_dir, command, &command_files, interrupted)?;
+
+        // A command may have deleted some of the files, which should then not
+        // be passed to the commands after it.
+        let modified_files = repository.get_modified_files(&task_files)?;
+
+        if !modified_files.is_empty() {
+            task_files.retain(|path| {
+                !modified_files.contains(*path)
+                    || fs::symlink_metadata(working_dir.join(path)).is_ok()
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs the tasks on up to as many threads as there are jobs. Tasks which share
+/// a staged file run one after another on the same thread, so that their
+/// commands never change a file at the same time. Every chain of tasks runs even
+/// when another one fails, and all of their errors are reported together.
+fn run_tasks_in_parallel(
+    options: &Options,
+    working_dir: &Path,
+    groups: Vec<(&Task, Vec<&PathBuf>)>,
+    interrupted: &AtomicBool,
+) -> Result<()> {
+    let chains = chain_overlapping_tasks(groups);
+
+    let job_count = options.jobs.min(chains.len());
+    let queue = Mutex::new(chains.into_iter());
+    let queue = &queue;
+
+    let results = thread::scope(|scope| {
+        let handles = (0..job_count)
+            .map(|_| {
+                scope.spawn(move |_| {
+                    // A repository cannot be shared between threads, so each one
+                    // opens its own to check which files the commands changed.
+                    let repository = match GitRepository::open_at(working_dir) {
+                        Ok(repository) => repository,
+                        Err(error) => return vec![Err(error)],
+                    };
+
+                    let mut results = vec![];
+
+                    loop {
+                        let next_chain = queue.lock().ok().and_then(|mut queue| queue.next());
+
+                        let chain = match next_chain {
+                            Some(chain) => chain,
+                            None => break,
+                        };
+
+                        // The tasks of a chain stop at the first failure, as they
+                        // do when running without jobs.
+                        for (task, task_files) in chain {
+                            let result = run_task(
+                                &repository,
+                                options,
+                                working_dir,
+                                task,
+                                task_files,
+                                interrupted,
+                            );
+
+                            let failed = result.is_err();
+                            results.push(result);
+
+                            if failed {
+                                break;
+                            }
+                        }
+                    }
+
+                    results
+                })
+            })
+            .collect_vec();
+
+        handles
+            .into_iter()
+            .flat_map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| vec![Err(anyhow!("A thread running commands panicked."))])
+            })
+            .collect_vec()
+    })
+    .map_err(|_| anyhow!("A thread running commands panicked."))?;
+
+    MultipleErrors::combine(results)
+}
+
+/// Puts the tasks which share staged files, directly or through other tasks,
+/// into the same chain, keeping them in the order they were given.
+fn chain_overlapping_tasks<'a>(
+    groups: Vec<(&'a Task, Vec<&'a PathBuf>)>,
+) -> Vec<Vec<(&'a Task, Vec<&'a PathBuf>)>> {
+    // Each task starts in a chain of its own, and chains are joined whenever
+    // one of their tasks shares a file with a later task.
+    let mut labels = (0..groups.len()).collect_vec();
+
+    for later in 0..groups.len() {
+        for earlier in 0..later {
+            let (_, later_files) = &groups[later];
+            let (_, earlier_files) = &groups[earlier];
+
+            if labels[later] != labels[earlier]
+                && later_files.iter().any(|path| earlier_files.contains(path))
+            {
+                let (old_label, new_label) = (labels[later], labels[earlier]);
+
+                for label in labels.iter_mut().filter(|label| **label == old_label) {
+                    *label = new_label;
+                }
+            }
+        }
+    }
+
+    let mut chains: Vec<(usize, Vec<(&Task, Vec<&PathBuf>)>)> = vec![];
+
+    for (group, label) in groups.into_iter().zip(labels) {
+        match chains
+            .iter_mut()
+            .find(|(chain_label, _)| *chain_label == label)
+        {
+            Some((_, chain)) => chain.push(group),
+            None => chains.push((label, vec![group])),
+        }
+    }
+
+    chains.into_iter().map(|(_, chain)| chain).collect()
+}
+
+/// Commands and the glob pattern of the staged files they run against.
+struct Task {
+    commands: Vec<String>,
+    matcher: Option<GlobMatcher>,
+}
+
+impl Task {
+    fn matches(&self, path: &Path) -> bool {
+        self.matcher
+            .as_ref()
+            .is_none_or(|matcher| matcher.is_match(path))
+    }
+}
+
+/// Prints the staged files which would be selected and the commands which would
+/// run against them, without saving a snapshot or running the commands.
+fn preview(options: &Options) -> Result<()> {
+    let repository = options.open_repository()?;
+
+    let tasks = Workflow::load_tasks(&repository, options)?;
+
+    let staged_files = Workflow::select_staged_files(&repository, options, &tasks)?;
+
+    if staged_files.is_empty() {
+        println!("No staged files were selected.");
+        return Ok(());
+    }
+
+    println!("Staged files:");
+    for path in staged_files.iter() {
+        println!("    {}", path.display());
+    }
+
+    let files_dir = Workflow::get_files_dir(&repository, options)?;
+
+    println!("Commands:");
+    for task in tasks.iter() {
+        let task_files = staged_files
+            .iter()
+            .filter(|path| task.matches(path))
+            .collect_vec();
+
+        if task_files.is_empty() {
+            continue;
+        }
+
+        let command_files = resolve_paths(
+            options,
+            repository.get_working_dir()?,
+            &files_dir,
+            &task_files,
+        );
+        let command_files = command_files.iter().collect_vec();
+
+        check_utf8_paths(options, &command_files)?;
+
+        for command in task.commands.iter() {
+            for invocation in build_invocations(options, command, &command_files) {
+                match invocation.stdin {
+                    Some(_) => println!("    {} < staged files", invocation.command_line),
+                    None => println!("    {}", invocation.command_line),
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks that the files can be written into the command line when they are
+/// passed as arguments. Paths which are not UTF-8 cannot be, and are reported
+/// rather than quietly left out of the command.
+fn check_utf8_paths(options: &Options, files: &[&PathBuf]) -> Result<()> {
+    if options.files_input == FilesInput::Arguments {
+        if let Some(path) = files.iter().find(|path| path.to_str().is_none()) {
+            return Err(NonUtf8PathError::new(path.to_path_buf()).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Determines the paths of the files to pass to the commands, which are kept
+/// relative to the directory the commands run in, being the root of the
+/// repository or the current directory, unless absolute paths are asked for. The
+/// snapshot keeps the paths relative to the root, since those get staged again.
+fn resolve_paths(
+    options: &Options,
+    working_dir: &Path,
+    files_dir: &Path,
+    files: &[&PathBuf],
+) -> Vec<PathBuf> {
+    files
+        .iter()
+        .map(|path| {
+            // Joining leaves a path that is already absolute as it is.
+            if options.absolute {
+                working_dir.join(path)
+            } else {
+                path.strip_prefix(files_dir).unwrap_or(path).to_path_buf()
+            }
+        })
+        .collect()
+}
+
+/// Environment variable set for the commands to the number of files they run
+/// against.
+const FILE_COUNT_VARIABLE: &str = "OFFSTAGE_FILE_COUNT";
+
+/// Environment variable set for the commands to the files they run against, each
+/// on its own line.
+const FILES_VARIABLE: &str = "OFFSTAGE_FILES";
+
+/// Runs a command with a shell against the files, stopping at the first
+/// invocation that fails or when interrupted.
+fn execute(
+    options: &Options,
+    working_dir: &Path,
+    command: &str,
+    files: &[&PathBuf],
+    interrupted: &AtomicBool,
+) -> Result<()> {
+    check_utf8_paths(options, files)?;
+
+    let progress = start_progress(options, command, files.len());
+
+    let inner = || -> Result<()> {
+        for invocation in build_invocations(options, command, files) {
+            if interrupted.load(Ordering::SeqCst) {
+                return Err(InterruptedError.into());
+            }
+
+            let shell = options.get_shell();
+            let mut expression = invoke_shell(&shell, &invocation.command_line).unchecked();
+
+            // A process group of its own is not in the foreground of the
+            // terminal, where a command reading from it would be stopped, so it
+            // is only worth it when the command may have to be killed.
+            if options.timeout.is_some() {
+                expression = start_process_group(expression);
+            }
+
+            // The variables describe the files of this invocation alone, since
+            // listing all of them could take the environment over the limit of
+            // the platform, which is shared with the arguments.
+            expression = expression.env(FILE_COUNT_VARIABLE, invocation.file_count.to_string());
+
+            expression = match &invocation.files_variable {
+                Some(files_variable) => expression.env(FILES_VARIABLE, files_variable),
+                None => expression.env_remove(FILES_VARIABLE),
+            };
+
+            if let Some(stdin) = invocation.stdin {
+                expression = expression.stdin_bytes(stdin);
+            }
+
+            // The paths of files are relative to the root of the repository
+            // unless asked to be relative to the current directory, so the
+            // commands run where those paths are right.
+            if !options.cwd_relative {
+                expression = expression.dir(working_dir);
+            }
+
+            // The output is held back while the spinner is shown, or while other
+            // commands run alongside, so that they do not interleave.
+            let capture_output = progress.is_some() || options.jobs > 1;
+
+            if capture_output {
+                expression = expression.stdout_capture().stderr_capture();
+            } else if options.json {
+                // Standard output is kept for the summary alone.
+                expression = expression.stdout_to_stderr();
+            }
+
+            let handle = expression.start()?;
+            let output = wait(
+                &handle,
+                &invocation.command_line,
+                options.timeout,
+                interrupted,
+            )?;
+
+            if capture_output {
+                let write = || write_output(options, &output);
+
+                match &progress {
+                    Some(progress) => progress.suspend(write)?,
+                    None => write()?,
+                }
+            }
+
+            // The exit code is kept in the error so that offstage can exit with
+            // it.
+            if !output.status.success() {
+                return Err(
+                    CommandError::new(invocation.command_line, output.status.code()).into(),
+                );
+            }
+        }
+
+        Ok(())
+    };
+
+    let result = inner();
+
+    if let Some(progress) = progress {
+        progress.finish_and_clear();
+    }
+
+    result
+}
+
+/// Writes the captured output of a command all at once, holding both standard
+/// output and standard error so that no other thread writes in between.
+fn write_output(options: &Options, output: &Output) -> io::Result<()> {
+    let stdout = io::stdout();
+    let stderr = io::stderr();
+    let mut stdout = stdout.lock();
+    let mut stderr = stderr.lock();
+
+    // Standard output is kept for the summary alone.
+    if options.json {
+        stderr.write_all(&output.stdout)?;
+    } else {
+        stdout.write_all(&output.stdout)?;
+        stdout.flush()?;
+    }
+
+    stderr.write_all(&output.stderr)
+}
+
+/// Shows a spinner with the number of files and the elapsed time on standard
+/// error while a command runs, if asked for and standard error is a terminal.
+fn start_progress(options: &Options, command: &str, file_count: usize) -> Option<ProgressBar> {
+    if !options.progress || options.json || options.jobs > 1 {
+        return None;
+    }
+
+    let progress = ProgressBar::new_spinner();
+
+    if progress.is_hidden() {
+        return None;
+    }
+
+    progress.set_style(ProgressStyle::default_spinner().template("{spinner} {msg} [{elapsed}]"));
+    progress.set_message(format!(
+        "Running `{}` against {} staged files",
+        command, file_count
+    ));
+    progress.enable_steady_tick(PROGRESS_TICK_INTERVAL);
+
+    Some(progress)
+}
+
+/// Waits for a running command to finish, killing it when interrupted or when it
+/// runs for longer than the timeout.
+fn wait(
+    handle: &Handle,
+    command_line: &str,
+    timeout: Option<Duration>,
+    interrupted: &AtomicBool,
+) -> Result<Output> {
+    let started = Instant::now();
+
+    loop {
+        let status = handle.try_wait();
+
+        // The interrupt is checked even when the command has finished, since
+        // the command likely received the same interrupt and failed because of
+        // it.
+        if interrupted.load(Ordering::SeqCst) {
+            // The command may have already exited, in which case only the
+            // processes it left behind are killed, if any.
+            kill_process_group(handle);
+            return Err(InterruptedError.into());
+        }
+
+        if let Some(output) = status? {
+            return Ok(output.clone());
+        }
+
+        if let Some(timeout) = timeout {
+            if started.elapsed() >= timeout {
+                kill_process_group(handle);
+                return Err(TimeoutError::new(command_line.to_string(), timeout).into());
+            }
+        }
+
+        sleep(POLL_INTERVAL);
+    }
+}
+
+/// Builds the expression which has the shell run the line. On Windows, the
+/// command interpreter gets its command line exactly as written.
+#[cfg(windows)]
+fn invoke_shell(shell: &Shell, command_line: &str) -> Expression {
+    use std::os::windows::process::CommandExt;
+
+    match shell.raw_arguments(command_line) {
+        Some(raw_arguments) => {
+            cmd(shell.program(), Vec::<String>::new()).before_spawn(move |command| {
+                command.raw_arg(&raw_arguments);
+                Ok(())
+            })
+        }
+        None => cmd(shell.program(), shell.arguments(command_line.to_string())),
+    }
+}
+
+#[cfg(not(windows))]
+fn invoke_shell(shell: &Shell, command_line: &str) -> Expression {
+    cmd(shell.program(), shell.arguments(command_line.to_string()))
+}
+
+/// Runs the shell in a process group of its own on Unix, so that the processes
+/// it starts, such as those of a command after a `;`, can be killed along with it
+/// instead of going on to modify the working directory after it is restored.
+#[cfg(unix)]
+fn start_process_group(expression: Expression) -> Expression {
+    use std::os::unix::process::CommandExt;
+
+    expression.before_spawn(|command| {
+        // Only async-signal-safe functions may run between fork and exec, which
+        // setpgid is.
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setpgid(0, 0) == 0 {
+                    Ok(())
+                } else {
+                    Err(io::Error::last_os_error())
+                }
+            });
+        }
+
+        Ok(())
+    })
+}
+
+#[cfg(not(unix))]
+fn start_process_group(expression: Expression) -> Expression {
+    expression
+}
+
+/// Kills a command along with every process it started, which are only known
+/// when it runs in a process group of its own.
+#[cfg(unix)]
+fn kill_process_group(handle: &Handle) {
+    for pid in handle.pids() {
+        // A negative id stands for the process group which the id leads. The
+        // group may be gone already, or never have been there without a
+        // timeout, which is fine.
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+        }
+    }
+
+    // This also waits for the shell, which is there to reap even if the kill
+    // above got to it first.
+    handle.kill().ok();
+}
+
+#[cfg(not(unix))]
+fn kill_process_group(handle: &Handle) {
+    handle.kill().ok();
+}
+
+/// A line for the shell to run, what to write to its standard input, and the
+/// variables describing the files it runs against.
+struct Invocation {
+    command_line: String,
+    stdin: Option<Vec<u8>>,
+    file_count: usize,
+    files_variable: Option<OsString>,
+}
+
+/// Builds the invocations of a command against the files, of which there is
+/// either one per file, or one per batch of files passed as arguments, or one
+/// for all files passed on standard input.
+fn build_invocations(options: &Options, command: &str, files: &[&PathBuf]) -> Vec<Invocation> {
+    let shell = options.get_shell();
+
+    let batches: Vec<Vec<&PathBuf>> = if options.per_file {
+        files.iter().map(|file| vec![*file]).collect()
+    } else if options.files_input == FilesInput::Arguments {
+        batch_files(&shell, command, files, options.max_command_length)
+    } else {
+        vec![files.to_vec()]
+    };
+
+    let terminator = match options.files_input {
+        FilesInput::Arguments => None,
+        FilesInput::Stdin => Some(b'\n'),
+        FilesInput::Stdin0 => Some(b'\0'),
+    };
+
+    batches
+        .iter()
+        .map(|batch| {
+            let (command_line, stdin) = match terminator {
+                None => (build_command_line(&shell, command, batch), None),
+                // The command is run as it is, without looking for placeholders,
+                // since the files are not passed as arguments.
+                Some(terminator) => (command.to_string(), Some(build_stdin(batch, terminator))),
+            };
+
+            Invocation {
+                command_line,
+                stdin,
+                file_count: batch.len(),
+                files_variable: build_files_variable(batch, options.max_command_length),
+            }
+        })
+        .collect()
+}
+
+/// Builds the standard input for a command, which is the files each followed by
+/// the terminator.
+fn build_stdin(files: &[&PathBuf], terminator: u8) -> Vec<u8> {
+    let mut stdin = vec![];
+
+    for path in files.iter().filter_map(|path| path_to_bytes(path)) {
+        stdin.extend_from_slice(path);
+        stdin.push(terminator);
+    }
+
+    stdin
+}
+
+/// Builds the value of the variable listing the files, one per line, which is
+/// left out when it is longer than the maximum length. This only happens when the
+/// files are not split into batches, such as when they are passed on standard
+/// input, or for a single file that is too long on its own.
+fn build_files_variable(files: &[&PathBuf], max_length: usize) -> Option<OsString> {
+    let mut variable = build_stdin(files, b'\n');
+    variable.pop();
+
+    if FILES_VARIABLE.len() + 1 + variable.len() > max_length {
+        return None;
+    }
+
+    Some(bytes_to_os_string(variable))
+}
+
+/// Gets the bytes of a path, which on Unix may be any bytes rather than only
+/// UTF-8.
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> Option<&[u8]> {
+    use std::os::unix::ffi::OsStrExt;
+
+    Some(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+fn path_to_bytes(path: &Path) -> Option<&[u8]> {
+    path.to_str().map(str::as_bytes)
+}
+
+#[cfg(unix)]
+fn bytes_to_os_string(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+fn bytes_to_os_string(bytes: Vec<u8>) -> OsString {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+/// Splits the files into batches so that the line built for the command against
+/// each batch, together with the variable listing the batch, is at most the
+/// maximum length, if possible. Because every file has to be in a batch, a file
+/// too long to fit in any is given a batch of its own.
+fn batch_files<'a>(
+    shell: &Shell,
+    command: &str,
+    files: &[&'a PathBuf],
+    max_length: usize,
+) -> Vec<Vec<&'a PathBuf>> {
+    // The files are repeated once per placeholder, or appended once after a
+    // space when there is no placeholder, which the length without any files
+    // already includes.
+    let repetitions = command
+        .split(' ')
+        .filter(|word| is_placeholder(word))
+        .count()
+        .max(1);
+    // The variable listing the files also counts, as its name followed by an
+    // equals sign and then each file followed by a newline.
+    let base_length = insert_files(command, "").len() + FILES_VARIABLE.len() + 1;
+
+    let mut batches = vec![];
+    let mut batch = vec![];
+    let mut batch_length = 0;
+    let mut variable_length = 0;
+
+    for file in files.iter() {
+        let file_length = file.to_str().map_or(0, |path| shell.quote(path).len());
+        let file_variable_length = path_to_bytes(file).map_or(0, |path| path.len() + 1);
+
+        if !batch.is_empty()
+            && base_length
+                + repetitions * (batch_length + 1 + file_length)
+                + variable_length
+                + file_variable_length
+                > max_length
+        {
+            batches.push(mem::take(&mut batch));
+            batch_length = 0;
+            variable_length = 0;
+        }
+
+        batch_length += if batch.is_empty() {
+            file_length
+        } else {
+            1 + file_length
+        };
+        variable_length += file_variable_length;
+        batch.push(*file);
+    }
+
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+
+    batches
+}
+
+/// Builds the line which the shell runs for a command against the files.
+fn build_command_line(shell: &Shell, command: &str, files: &[&PathBuf]) -> String {
+    let files = files
+        .iter()
+        .filter_map(|path| path.to_str())
+        .map(|path| shell.quote(path))
+        .join(" ");
+
+    insert_files(command, &files)
+}
+
+/// Parses a glob pattern, which ignores case if asked for.
+fn build_glob(pattern: &str, options: &Options) -> Result<Glob> {
+    Ok(GlobBuilder::new(pattern)
+        .case_insensitive(options.ignore_case)
+        .build()?)
+}
+
+/// Compiles glob patterns into a set which matches a path if any one of the
+/// patterns matches it.
+fn build_glob_set(patterns: &[String], options: &Options) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+
+    for pattern in patterns {
+        builder.add(build_glob(pattern, options)?);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// Words in a command that are replaced with the staged files.
+const FILES_PLACEHOLDERS: &[&str] = &["{}", "{files}"];
+
+/// Inserts the files into the command in place of every placeholder word, or
+/// appends them to the end of the command if it does not have a placeholder.
+fn insert_files(command: &str, files: &str) -> String {
+    if command.split(' ').any(is_placeholder) {
+        command
+            .split(' ')
+            .map(|word| if is_placeholder(word) { files } else { word })
+            .join(" ")
+    } else {
+        format!("{} {}", command, files)
+    }
+}
+
+fn is_placeholder(word: &str) -> bool {
+    FILES_PLACEHOLDERS.contains(&word)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{batch_files, insert_files};
+    use crate::shell::Shell;
+    use std::path::{Path, PathBuf};
+
+    fn posix_shell() -> Shell<'static> {
+        Shell::new(Path::new("sh"), None)
+    }
+
+    #[test]
+    fn batch_files_keeps_command_lines_within_max_length() {
+        let a = PathBuf::from("A.js");
+        let b = PathBuf::from("B.js");
+        let c = PathBuf::from("C.js");
+
+        // "echo 'A.js' 'B.js'" is 18 characters long, and the variable
+        // "OFFSTAGE_FILES=A.js\nB.js\n" is 25 more.
+        assert_eq!(
+            batch_files(&posix_shell(), "echo", &[&a, &b, &c], 43),
+            vec![vec![&a, &b], vec![&c]]
+        );
+        assert_eq!(
+            batch_files(&posix_shell(), "echo {} {}", &[&a, &b, &c], 43),
+            vec![vec![&a], vec![&b], vec![&c]]
+        );
+    }
+
+    #[test]
+    fn batch_files_gives_long_file_its_own_batch() {
+        let a = PathBuf::from("A.js");
+        let b = PathBuf::from("B.js");
+
+        assert_eq!(
+            batch_files(&posix_shell(), "echo", &[&a, &b], 1),
+            vec![vec![&a], vec![&b]]
+        );
+    }
+
+    #[test]
+    fn insert_files_appends_without_placeholder() {
+        assert_eq!(
+            insert_files("prettier --write", "'A.js' 'B.js'"),
+            "prettier --write 'A.js' 'B.js'"
+        );
+    }
+
+    #[test]
+    fn insert_files_replaces_placeholder() {
+        assert_eq!(
+            insert_files("prettier {} --write", "'A.js' 'B.js'"),
+            "prettier 'A.js' 'B.js' --write"
+        );
+    }
+
+    #[test]
+    fn insert_files_replaces_every_placeholder() {
+        assert_eq!(
+            insert_files("prettier --write {} && eslint {files}", "'A.js'"),
+            "prettier --write 'A.js' && eslint 'A.js'"
+        );
+    }
+
+    #[test]
+    fn insert_files_ignores_placeholder_inside_word() {
+        assert_eq!(insert_files("awk '{}'", "'A.txt'"), "awk '{}' 'A.txt'");
+    }
 }
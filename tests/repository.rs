@@ -1,6 +1,6 @@
 use anyhow::{anyhow, Result};
 use git2::{Commit, ErrorCode, Repository, Signature};
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::slice;
@@ -36,6 +36,12 @@ impl TestRepository {
         Ok(path)
     }
 
+    pub fn create_file(&self, name: &str, contents: &str) -> Result<PathBuf> {
+        let path = self.get_working_dir()?.join(name);
+        writeln!(File::create(&path)?, "{}", contents)?;
+        Ok(path)
+    }
+
     pub fn stage_path<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
         let working_dir = self.get_working_dir()?;
         let relative_path = path.as_ref().strip_prefix(working_dir)?;
@@ -47,6 +53,34 @@ impl TestRepository {
         Ok(())
     }
 
+    pub fn delete_path<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let working_dir = self.get_working_dir()?;
+        let relative_path = path.as_ref().strip_prefix(working_dir)?;
+
+        fs::remove_file(&path)?;
+
+        let mut index = self.repository.index()?;
+        index.remove_path(relative_path)?;
+        index.write()?;
+
+        Ok(())
+    }
+
+    pub fn move_path<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) -> Result<()> {
+        let working_dir = self.get_working_dir()?;
+        let from_relative_path = from.as_ref().strip_prefix(working_dir)?;
+        let to_relative_path = to.as_ref().strip_prefix(working_dir)?;
+
+        fs::rename(&from, &to)?;
+
+        let mut index = self.repository.index()?;
+        index.remove_path(from_relative_path)?;
+        index.add_path(to_relative_path)?;
+        index.write()?;
+
+        Ok(())
+    }
+
     pub fn commit(&mut self, message: &str) -> Result<()> {
         let index = self.repository.index()?.write_tree()?;
         let signature = Self::get_signature()?;
@@ -76,6 +110,65 @@ impl TestRepository {
         Ok(())
     }
 
+    pub fn read_committed_file(&self, name: &str) -> Result<Option<String>> {
+        let tree = self.repository.head()?.peel_to_tree()?;
+
+        let entry = match tree.get_name(name) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+
+        let blob = entry.to_object(&self.repository)?.peel_to_blob()?;
+
+        Ok(Some(String::from_utf8(blob.content().to_vec())?))
+    }
+
+    pub fn add_worktree<P: AsRef<Path>>(&self, name: &str, path: P) -> Result<Self> {
+        let worktree = self.repository.worktree(name, path.as_ref(), None)?;
+        let repository = Repository::open_from_worktree(&worktree)?;
+        Ok(Self { repository })
+    }
+
+    pub fn start_merge(&self) -> Result<()> {
+        let head_id = self.repository.head()?.peel_to_commit()?.id();
+
+        fs::write(
+            self.get_git_dir().join("MERGE_HEAD"),
+            format!("{}\n", head_id),
+        )?;
+        fs::write(self.get_git_dir().join("MERGE_MODE"), "no-ff")?;
+        fs::write(self.get_git_dir().join("MERGE_MSG"), "Merge a branch.\n")?;
+
+        Ok(())
+    }
+
+    pub fn start_rebase(&self) -> Result<()> {
+        let head_id = self.repository.head()?.peel_to_commit()?.id();
+        let rebase_dir = self.get_git_dir().join("rebase-merge");
+
+        fs::create_dir(&rebase_dir)?;
+        fs::write(rebase_dir.join("head-name"), "refs/heads/master\n")?;
+        fs::write(rebase_dir.join("onto"), format!("{}\n", head_id))?;
+        fs::write(rebase_dir.join("orig-head"), format!("{}\n", head_id))?;
+
+        Ok(())
+    }
+
+    pub fn get_git_dir(&self) -> &Path {
+        self.repository.path()
+    }
+
+    pub fn count_stashes(&mut self) -> Result<usize> {
+        let mut count = 0;
+
+        self.repository.stash_foreach(|_, _, _| {
+            count += 1;
+            true
+        })?;
+
+        Ok(count)
+    }
+
     fn get_working_dir(&self) -> Result<&Path> {
         self.repository
             .workdir()
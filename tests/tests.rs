@@ -1,17 +1,21 @@
 use anyhow::Result;
 use duct::cmd;
 use repository::{TestRepository, LICENSE, README};
+use serde_json::{json, Value};
 use std::env;
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::sync::Once;
+use std::thread;
+use std::time::{Duration, Instant};
 
 mod repository;
 
 const OUTPUTS_DIR: &str = "tests/outputs";
 const BINARY_NAME: &str = env!("CARGO_BIN_EXE_offstage");
+const CONFIG: &str = ".offstage.toml";
 
 static INIT: Once = Once::new();
 
@@ -172,6 +176,3294 @@ fn unstaged_file_remains_after_command_succeeds() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn staged_file_with_spaces_is_passed_as_one_argument() -> Result<()> {
+    // Given
+    let working_dir = initialize("staged_file_with_spaces_is_passed_as_one_argument")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let notes = repository.create_file("my notes.md", "Some notes.")?;
+    repository.stage_path(&notes)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "printf", "'<%s>\\n'")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert!(
+        stdout.contains("<my notes.md>"),
+        "Output \"{}\" should contain \"<my notes.md>\".",
+        stdout
+    );
+
+    Ok(())
+}
+
+#[test]
+fn staged_file_matching_any_filter_is_passed_to_command() -> Result<()> {
+    // Given
+    let working_dir = initialize("staged_file_matching_any_filter_is_passed_to_command")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "--filter", "*.md", "--filter", LICENSE, "echo")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert!(
+        stdout.contains(LICENSE),
+        "Output \"{}\" should contain \"{}\".",
+        stdout,
+        LICENSE
+    );
+
+    assert!(
+        !stdout.contains(README),
+        "Output \"{}\" should not contain filtered out file \"{}\".",
+        stdout,
+        README
+    );
+
+    Ok(())
+}
+
+#[test]
+fn staged_file_matching_filter_and_exclude_is_excluded() -> Result<()> {
+    // Given
+    let working_dir = initialize("staged_file_matching_filter_and_exclude_is_excluded")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "--filter", "*", "--exclude", LICENSE, "echo")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert!(
+        stdout.contains(README),
+        "Output \"{}\" should contain \"{}\".",
+        stdout,
+        README
+    );
+
+    assert!(
+        !stdout.contains(LICENSE),
+        "Output \"{}\" should not contain excluded file \"{}\".",
+        stdout,
+        LICENSE
+    );
+
+    Ok(())
+}
+
+#[test]
+fn all_staged_files_excluded_skips_command() -> Result<()> {
+    // Given
+    let working_dir = initialize("all_staged_files_excluded_skips_command")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let marker = "marker";
+    let stdout = cmd!(BINARY_NAME, "--exclude", "*", "echo", marker)
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert!(
+        !stdout.contains(marker),
+        "Output \"{}\" should not contain \"{}\".",
+        stdout,
+        marker
+    );
+
+    Ok(())
+}
+
+#[test]
+fn config_file_runs_each_command_against_its_files() -> Result<()> {
+    // Given
+    let working_dir = initialize("config_file_runs_each_command_against_its_files")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    repository.create_file(
+        CONFIG,
+        r#"
+"*.md" = "echo markdown"
+"LICENSE" = "echo license"
+"*.rs" = "echo rust"
+"#,
+    )?;
+
+    let notes = repository.create_file("notes.md", "Some notes.")?;
+    repository.stage_path(&notes)?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME).dir(&working_dir).read()?;
+
+    // Then
+    assert!(
+        stdout.contains("markdown notes.md"),
+        "Output \"{}\" should contain the markdown command.",
+        stdout
+    );
+
+    assert!(
+        stdout.contains("license LICENSE"),
+        "Output \"{}\" should contain the license command.",
+        stdout
+    );
+
+    assert!(
+        !stdout.contains("rust"),
+        "Output \"{}\" should not contain the command without staged files.",
+        stdout
+    );
+
+    Ok(())
+}
+
+#[test]
+fn invalid_config_file_reports_its_path() -> Result<()> {
+    // Given
+    let working_dir = initialize("invalid_config_file_reports_its_path")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    repository.create_file(CONFIG, "\"*.md\" = ")?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let output = cmd!(BINARY_NAME)
+        .dir(&working_dir)
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+
+    // Then
+    assert!(!output.status.success(), "The command should fail.");
+
+    let stderr = String::from_utf8(output.stderr)?;
+
+    assert!(
+        stderr.contains(CONFIG),
+        "Error \"{}\" should contain \"{}\".",
+        stderr,
+        CONFIG
+    );
+
+    Ok(())
+}
+
+#[test]
+fn commands_run_in_order() -> Result<()> {
+    // Given
+    let working_dir = initialize("commands_run_in_order")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let stdout = cmd!(
+        BINARY_NAME,
+        "--command",
+        "echo first",
+        "--command",
+        "echo second",
+        "echo",
+        "third"
+    )
+    .dir(&working_dir)
+    .read()?;
+
+    // Then
+    assert_eq!(
+        stdout, "first README\nsecond README\nthird README",
+        "The commands should run in order."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn failed_command_skips_later_commands() -> Result<()> {
+    // Given
+    let working_dir = initialize("failed_command_skips_later_commands")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let marker = "marker";
+    let output = cmd!(
+        BINARY_NAME,
+        "--command",
+        "false",
+        "--command",
+        format!("echo {}", marker)
+    )
+    .dir(&working_dir)
+    .stdout_capture()
+    .unchecked()
+    .run()?;
+
+    // Then
+    assert!(!output.status.success(), "The command should fail.");
+
+    let stdout = String::from_utf8(output.stdout)?;
+
+    assert!(
+        !stdout.contains(marker),
+        "Output \"{}\" should not contain \"{}\".",
+        stdout,
+        marker
+    );
+
+    Ok(())
+}
+
+#[test]
+fn file_deleted_by_command_is_not_passed_to_later_commands() -> Result<()> {
+    // Given
+    let working_dir = initialize("file_deleted_by_command_is_not_passed_to_later_commands")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+
+    // When
+    let stdout = cmd!(
+        BINARY_NAME,
+        "--command",
+        format!("rm {}; true", LICENSE),
+        "echo"
+    )
+    .dir(&working_dir)
+    .read()?;
+
+    // Then
+    assert!(
+        stdout.contains(README),
+        "Output \"{}\" should contain \"{}\".",
+        stdout,
+        README
+    );
+
+    assert!(
+        !stdout.contains(LICENSE),
+        "Output \"{}\" should not contain deleted file \"{}\".",
+        stdout,
+        LICENSE
+    );
+
+    Ok(())
+}
+
+#[test]
+fn dry_run_prints_command_without_running_it() -> Result<()> {
+    // Given
+    let working_dir = initialize("dry_run_prints_command_without_running_it")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let marker = "marker";
+    let stdout = cmd!(BINARY_NAME, "--dry-run", "touch", marker)
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert!(
+        stdout.contains(&format!("touch {} 'README'", marker)),
+        "Output \"{}\" should contain the command.",
+        stdout
+    );
+
+    assert!(
+        !working_dir.join(marker).exists(),
+        "The command should not have run."
+    );
+
+    assert_eq!(
+        repository.count_stashes()?,
+        0,
+        "There should not be any stashes."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn failed_restore_reports_command_error_too() -> Result<()> {
+    // Given
+    let working_dir = initialize("failed_restore_reports_command_error_too")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let output = cmd!(
+        BINARY_NAME,
+        "--command",
+        "rm -f .git/refs/stash .git/logs/refs/stash; exit 1"
+    )
+    .dir(&working_dir)
+    .stderr_capture()
+    .unchecked()
+    .run()?;
+
+    // Then
+    assert!(!output.status.success(), "The command should fail.");
+
+    let stderr = String::from_utf8(output.stderr)?;
+
+    assert!(
+        stderr.contains("exited with code 1"),
+        "Error \"{}\" should contain the command failure.",
+        stderr
+    );
+
+    assert!(
+        stderr.contains("restoring snapshot"),
+        "Error \"{}\" should contain the restore failure.",
+        stderr
+    );
+
+    Ok(())
+}
+
+#[test]
+fn command_undoing_staged_changes_succeeds_with_message() -> Result<()> {
+    // Given
+    let working_dir = initialize("command_undoing_staged_changes_succeeds_with_message")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let output = cmd!(
+        BINARY_NAME,
+        "--command",
+        format!("printf 'An example README.\\n' > {}; true", README)
+    )
+    .dir(&working_dir)
+    .stderr_capture()
+    .unchecked()
+    .run()?;
+
+    // Then
+    let stderr = String::from_utf8(output.stderr)?;
+
+    assert!(
+        output.status.success(),
+        "The command should succeed, but failed with \"{}\".",
+        stderr
+    );
+
+    assert!(
+        stderr.contains("Prevented an empty git commit"),
+        "Output \"{}\" should explain that the commit would be empty.",
+        stderr
+    );
+
+    Ok(())
+}
+
+#[test]
+fn per_file_runs_command_once_per_staged_file() -> Result<()> {
+    // Given
+    let working_dir = initialize("per_file_runs_command_once_per_staged_file")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "--per-file", "echo", "file")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    let mut lines = stdout.lines().collect::<Vec<_>>();
+    lines.sort();
+
+    assert_eq!(
+        lines,
+        vec![format!("file {}", LICENSE), format!("file {}", README)],
+        "The command should run once per staged file."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn per_file_stops_at_first_failure() -> Result<()> {
+    // Given
+    let working_dir = initialize("per_file_stops_at_first_failure")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+
+    // When
+    let output = cmd!(BINARY_NAME, "--per-file", "echo file; exit 3;")
+        .dir(&working_dir)
+        .stdout_capture()
+        .unchecked()
+        .run()?;
+
+    // Then
+    assert!(!output.status.success(), "The command should fail.");
+
+    let stdout = String::from_utf8(output.stdout)?;
+
+    assert_eq!(
+        stdout.lines().count(),
+        1,
+        "Output \"{}\" should only come from the first invocation.",
+        stdout
+    );
+
+    Ok(())
+}
+
+#[test]
+fn long_command_line_is_split_into_batches() -> Result<()> {
+    // Given
+    let working_dir = initialize("long_command_line_is_split_into_batches")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "--max-command-length", "1", "echo", "batch")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert_eq!(
+        stdout
+            .lines()
+            .filter(|line| line.starts_with("batch"))
+            .count(),
+        2,
+        "Output \"{}\" should come from one invocation per batch.",
+        stdout
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupt_restores_snapshot() -> Result<()> {
+    // Given
+    let working_dir = initialize("interrupt_restores_snapshot")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let handle = cmd!(BINARY_NAME, "sleep 10 #")
+        .dir(&working_dir)
+        .stderr_capture()
+        .unchecked()
+        .start()?;
+
+    thread::sleep(Duration::from_secs(1));
+
+    for pid in handle.pids() {
+        cmd!("kill", "-INT", pid.to_string()).run()?;
+    }
+
+    let output = handle.wait()?;
+
+    // Then
+    assert!(!output.status.success(), "The command should fail.");
+
+    assert_eq!(
+        repository.count_stashes()?,
+        0,
+        "The backup stash should have been cleaned up."
+    );
+
+    let readme_contents = fs::read_to_string(&readme)?;
+
+    assert!(
+        readme_contents.contains("A new line."),
+        "The staged file {} should still contain its changes.",
+        README
+    );
+
+    Ok(())
+}
+
+#[test]
+fn keep_stash_keeps_backup_after_command_fails() -> Result<()> {
+    // Given
+    let working_dir = initialize("keep_stash_keeps_backup_after_command_fails")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let output = cmd!(BINARY_NAME, "--keep-stash", "false")
+        .dir(&working_dir)
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+
+    // Then
+    assert!(!output.status.success(), "The command should fail.");
+
+    let stderr = String::from_utf8(output.stderr)?;
+
+    assert!(
+        stderr.contains("Kept the backup stash"),
+        "Output \"{}\" should mention the kept backup stash.",
+        stderr
+    );
+
+    assert_eq!(
+        repository.count_stashes()?,
+        1,
+        "The backup stash should have been kept."
+    );
+
+    // When
+    cmd!(BINARY_NAME, "--gc-stashes", "true")
+        .dir(&working_dir)
+        .stderr_null()
+        .run()?;
+
+    // Then
+    assert_eq!(
+        repository.count_stashes()?,
+        1,
+        "The kept backup stash should not be dropped as left behind."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn stdin_passes_staged_files_as_lines() -> Result<()> {
+    // Given
+    let working_dir = initialize("stdin_passes_staged_files_as_lines")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "--stdin", "cat; echo {}")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    let mut lines = stdout.lines().collect::<Vec<_>>();
+    lines.sort();
+
+    assert_eq!(
+        lines,
+        vec![LICENSE, README, "{}"],
+        "The staged files should be passed on standard input instead of as arguments."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn stdin0_passes_staged_files_separated_by_nul() -> Result<()> {
+    // Given
+    let working_dir = initialize("stdin0_passes_staged_files_separated_by_nul")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "--stdin0", "tr '\\0' ':'")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    for file in &[LICENSE, README] {
+        assert!(
+            stdout.contains(&format!("{}:", file)),
+            "Output \"{}\" should contain \"{}\" followed by a NUL character.",
+            stdout,
+            file
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn diff_filter_selects_staged_files_by_change() -> Result<()> {
+    // Given
+    let working_dir = initialize("diff_filter_selects_staged_files_by_change")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+    repository.commit("Add a license file.")?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    repository.delete_path(&license)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "--diff-filter", "AM", "echo")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert!(
+        stdout.contains(README),
+        "Output \"{}\" should contain modified file \"{}\".",
+        stdout,
+        README
+    );
+
+    assert!(
+        !stdout.contains(LICENSE),
+        "Output \"{}\" should not contain deleted file \"{}\".",
+        stdout,
+        LICENSE
+    );
+
+    Ok(())
+}
+
+#[test]
+fn diff_filter_selects_copied_files() -> Result<()> {
+    // Given
+    let working_dir = initialize("diff_filter_selects_copied_files")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let notes_contents = "The first note.\nThe second note.\nThe third note.\nThe fourth note.";
+
+    let notes = repository.create_file("NOTES", notes_contents)?;
+    repository.stage_path(&notes)?;
+    repository.commit("Add notes.")?;
+
+    let copied_notes = repository.create_file("COPIED_NOTES", notes_contents)?;
+    repository.stage_path(&copied_notes)?;
+
+    append_line(&notes, "The fifth note.")?;
+    repository.stage_path(&notes)?;
+
+    // When
+    let copied_stdout = cmd!(BINARY_NAME, "--diff-filter", "C", "list-files")
+        .dir(&working_dir)
+        .read()?;
+
+    let added_stdout = cmd!(BINARY_NAME, "--diff-filter", "A", "list-files")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert_eq!(
+        copied_stdout, "COPIED_NOTES",
+        "The copied file should be selected."
+    );
+
+    assert_eq!(
+        added_stdout, "",
+        "The copied file should not count as added."
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn diff_filter_selects_type_changed_files() -> Result<()> {
+    // Given
+    let working_dir = initialize("diff_filter_selects_type_changed_files")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let link = working_dir.join("LINK");
+    std::os::unix::fs::symlink(README, &link)?;
+    repository.stage_path(&link)?;
+    repository.commit("Add a link.")?;
+
+    fs::remove_file(&link)?;
+    fs::write(&link, "No longer a link.\n")?;
+    repository.stage_path(&link)?;
+
+    // When
+    let type_changed_stdout = cmd!(BINARY_NAME, "--diff-filter", "T", "list-files")
+        .dir(&working_dir)
+        .read()?;
+
+    let added_stdout = cmd!(BINARY_NAME, "--diff-filter", "A", "list-files")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert_eq!(
+        type_changed_stdout, "LINK",
+        "The file which replaced the link should be selected."
+    );
+
+    assert_eq!(
+        added_stdout, "",
+        "The file which replaced the link should not count as added."
+    );
+
+    // When
+    repository.commit("Replace the link.")?;
+
+    fs::remove_file(&link)?;
+    std::os::unix::fs::symlink(README, &link)?;
+    repository.stage_path(&link)?;
+
+    let link_stdout = cmd!(BINARY_NAME, "--diff-filter", "T", "list-files")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert_eq!(
+        link_stdout, "LINK",
+        "The link which replaced the file should be selected."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn absolute_passes_absolute_paths() -> Result<()> {
+    // Given
+    let working_dir = initialize("absolute_passes_absolute_paths")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "--absolute", "echo")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert_eq!(
+        Path::new(stdout.trim()),
+        readme,
+        "The staged file should be passed as an absolute path."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn failed_command_exit_code_is_returned() -> Result<()> {
+    // Given
+    let working_dir = initialize("failed_command_exit_code_is_returned")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let output = cmd!(BINARY_NAME, "exit 3")
+        .dir(&working_dir)
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+
+    // Then
+    assert_eq!(
+        output.status.code(),
+        Some(3),
+        "Offstage should exit with the exit code of the command."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn verbose_logs_git_operations() -> Result<()> {
+    // Given
+    let working_dir = initialize("verbose_logs_git_operations")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let output = cmd!(BINARY_NAME, "--verbose", "true")
+        .dir(&working_dir)
+        .stderr_capture()
+        .run()?;
+
+    // Then
+    let stderr = String::from_utf8(output.stderr)?;
+
+    assert!(
+        stderr.contains("Created backup stash"),
+        "Error output \"{}\" should log the backup stash.",
+        stderr
+    );
+
+    Ok(())
+}
+
+#[test]
+fn quiet_by_default() -> Result<()> {
+    // Given
+    let working_dir = initialize("quiet_by_default")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let output = cmd!(BINARY_NAME, "true")
+        .dir(&working_dir)
+        .stderr_capture()
+        .run()?;
+
+    // Then
+    let stderr = String::from_utf8(output.stderr)?;
+
+    assert!(
+        stderr.is_empty(),
+        "Error output \"{}\" should be empty.",
+        stderr
+    );
+
+    Ok(())
+}
+
+#[test]
+fn json_prints_summary() -> Result<()> {
+    // Given
+    let working_dir = initialize("json_prints_summary")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let output = cmd!(BINARY_NAME, "--json", "--keep-stash", "echo {}; exit 3")
+        .dir(&working_dir)
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+
+    // Then
+    let summary: Value = serde_json::from_slice(&output.stdout)?;
+
+    assert_eq!(summary["staged_files"], json!([README]));
+    assert_eq!(summary["command_ran"], json!(true));
+    assert_eq!(summary["exit_code"], json!(3));
+    assert_eq!(summary["restored"], json!(true));
+    assert!(
+        summary["backup_stash"].is_string(),
+        "Summary \"{}\" should contain the kept backup stash.",
+        summary
+    );
+
+    let stderr = String::from_utf8(output.stderr)?;
+
+    assert!(
+        stderr.contains(README),
+        "Error output \"{}\" should contain the output of the command.",
+        stderr
+    );
+
+    Ok(())
+}
+
+#[test]
+fn renamed_file_is_staged_at_new_path() -> Result<()> {
+    // Given
+    let working_dir = initialize("renamed_file_is_staged_at_new_path")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let notes = repository.create_file(
+        "NOTES",
+        "The first note.\nThe second note.\nThe third note.\nThe fourth note.",
+    )?;
+    repository.stage_path(&notes)?;
+    repository.commit("Add notes.")?;
+
+    let moved_notes = working_dir.join("MOVED_NOTES");
+    repository.move_path(&notes, &moved_notes)?;
+    append_line(&moved_notes, "The fifth note.")?;
+    repository.stage_path(&moved_notes)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "printf 'The sixth note.\\n' | tee -a")
+        .dir(&working_dir)
+        .read()?;
+
+    repository.commit("Move notes.")?;
+
+    // Then
+    assert_eq!(stdout, "The sixth note.");
+
+    assert_eq!(
+        repository.read_committed_file("MOVED_NOTES")?.as_deref(),
+        Some(
+            "The first note.\nThe second note.\nThe third note.\nThe fourth note.\n\
+             The fifth note.\nThe sixth note.\n"
+        ),
+        "The renamed file should be committed with the modifications."
+    );
+
+    assert_eq!(
+        repository.read_committed_file("NOTES")?,
+        None,
+        "The old path of the renamed file should not be committed."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn text_only_leaves_out_binary_files() -> Result<()> {
+    // Given
+    let working_dir = initialize("text_only_leaves_out_binary_files")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    let image = working_dir.join("image.png");
+    fs::write(&image, b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR")?;
+    repository.stage_path(&image)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "--text-only", "echo")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert_eq!(
+        stdout, README,
+        "Only the text file should be passed to the command."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn repo_runs_against_repository_elsewhere() -> Result<()> {
+    // Given
+    let working_dir = initialize("repo_runs_against_repository_elsewhere")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "--repo", &working_dir, "cat")
+        .dir(env::temp_dir())
+        .read()?;
+
+    // Then
+    assert_eq!(
+        stdout, "An example README.\nA new line.",
+        "The command should run against the repository in its working directory."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn command_runs_in_root_from_nested_directory() -> Result<()> {
+    // Given
+    let working_dir = initialize("command_runs_in_root_from_nested_directory")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let nested_dir = working_dir.join("nested");
+    fs::create_dir(&nested_dir)?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "cat").dir(&nested_dir).read()?;
+
+    // Then
+    assert_eq!(
+        stdout, "An example README.\nA new line.",
+        "The command should run in the root of the repository."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn untracked_file_is_restored_after_command_fails() -> Result<()> {
+    // Given
+    let working_dir = initialize("untracked_file_is_restored_after_command_fails")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    let license = repository.create_license()?;
+
+    // When
+    let output = cmd!(BINARY_NAME, format!("rm {}; exit 1", LICENSE))
+        .dir(&working_dir)
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+
+    // Then
+    assert!(!output.status.success(), "The command should fail.");
+
+    assert_eq!(
+        fs::read_to_string(&license)?,
+        "Free as in freedom.\n",
+        "The untracked file {} should be restored.",
+        LICENSE
+    );
+
+    Ok(())
+}
+
+#[test]
+fn conflict_with_unstaged_changes_names_file() -> Result<()> {
+    // Given
+    let working_dir = initialize("conflict_with_unstaged_changes_names_file")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+    append_line(&readme, "An unstaged line.")?;
+
+    // When
+    let output = cmd!(BINARY_NAME, format!("printf 'Rewritten.\\n' > {}", README))
+        .dir(&working_dir)
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+
+    // Then
+    assert!(!output.status.success(), "The command should fail.");
+
+    let stderr = String::from_utf8(output.stderr)?;
+
+    assert!(
+        stderr.contains(&format!("unstaged changes of {}", README)),
+        "Error \"{}\" should name the conflicting file.",
+        stderr
+    );
+
+    assert_eq!(
+        fs::read_to_string(&readme)?,
+        "An example README.\nA new line.\nAn unstaged line.\n",
+        "The working directory should be restored."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn ignore_case_matches_filter_regardless_of_case() -> Result<()> {
+    // Given
+    let working_dir = initialize("ignore_case_matches_filter_regardless_of_case")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let photo = repository.create_file("PHOTO.JPG", "Not really a photo.")?;
+    repository.stage_path(&photo)?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "--ignore-case", "--filter", "*.jpg", "echo")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert_eq!(
+        stdout, "PHOTO.JPG",
+        "The filter should match the staged file regardless of case."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn list_files_prints_selected_staged_files() -> Result<()> {
+    // Given
+    let working_dir = initialize("list_files_prints_selected_staged_files")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "--filter", LICENSE, "list-files")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert_eq!(
+        stdout, LICENSE,
+        "Only the staged file passing the filter should be printed."
+    );
+
+    assert_eq!(
+        repository.count_stashes()?,
+        0,
+        "No snapshot should have been saved."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn environment_variables_describe_staged_files() -> Result<()> {
+    // Given
+    let working_dir = initialize("environment_variables_describe_staged_files")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+
+    // When
+    let stdout = cmd!(
+        BINARY_NAME,
+        "echo $OFFSTAGE_FILE_COUNT; printf '%s\\n' \"$OFFSTAGE_FILES\" | sort; true {}"
+    )
+    .dir(&working_dir)
+    .read()?;
+
+    // Then
+    assert_eq!(
+        stdout,
+        format!("2\n{}\n{}", LICENSE, README),
+        "The environment variables should describe the staged files."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn environment_variables_describe_each_batch() -> Result<()> {
+    // Given
+    let working_dir = initialize("environment_variables_describe_each_batch")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+
+    // When
+    let stdout = cmd!(
+        BINARY_NAME,
+        "--per-file",
+        "echo \"$OFFSTAGE_FILE_COUNT $OFFSTAGE_FILES\"; true {}"
+    )
+    .dir(&working_dir)
+    .read()?;
+
+    // Then
+    assert_eq!(
+        stdout,
+        format!("1 {}\n1 {}", LICENSE, README),
+        "The environment variables should describe the file of each invocation."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn files_variable_longer_than_max_command_length_is_left_out() -> Result<()> {
+    // Given
+    let working_dir = initialize("files_variable_longer_than_max_command_length_is_left_out")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+
+    // When
+    let stdout = cmd!(
+        BINARY_NAME,
+        "--stdin",
+        "--max-command-length",
+        "20",
+        "echo \"$OFFSTAGE_FILE_COUNT ${OFFSTAGE_FILES-unset}\"; cat"
+    )
+    .dir(&working_dir)
+    .env(
+        "OFFSTAGE_FILES",
+        "A variable of the environment offstage runs in.",
+    )
+    .read()?;
+
+    // Then
+    assert_eq!(
+        stdout,
+        format!("2 unset\n{}\n{}", LICENSE, README),
+        "The variable listing the files should be left out, but not the files."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn timeout_kills_command_and_restores_snapshot() -> Result<()> {
+    // Given
+    let working_dir = initialize("timeout_kills_command_and_restores_snapshot")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let started = Instant::now();
+
+    // The comment keeps the staged files from being passed to sleep.
+    let output = cmd!(BINARY_NAME, "--timeout", "1", "sleep 10 #")
+        .dir(&working_dir)
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+
+    let elapsed = started.elapsed();
+
+    // Then
+    assert!(!output.status.success(), "The command should fail.");
+
+    let stderr = String::from_utf8(output.stderr)?;
+
+    assert!(
+        stderr.contains("was killed after running for longer than 1 seconds"),
+        "Error \"{}\" should report the timeout.",
+        stderr
+    );
+
+    assert!(
+        elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(5),
+        "The command should be killed after the timeout, but it took {:?}.",
+        elapsed
+    );
+
+    assert_eq!(
+        fs::read_to_string(&readme)?,
+        "An example README.\nA new line.\n",
+        "The working directory should be restored."
+    );
+
+    assert_eq!(
+        repository.count_stashes()?,
+        0,
+        "No stash should be left behind."
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn timeout_kills_processes_started_by_command() -> Result<()> {
+    // Given
+    let working_dir = initialize("timeout_kills_processes_started_by_command")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    let late_file = working_dir.join("LATE");
+
+    // When
+    let output = cmd!(
+        BINARY_NAME,
+        "--timeout",
+        "1",
+        "sh -c 'sleep 2; echo late > LATE' #"
+    )
+    .dir(&working_dir)
+    .stderr_null()
+    .unchecked()
+    .run()?;
+
+    thread::sleep(Duration::from_secs(3));
+
+    // Then
+    assert!(!output.status.success(), "The command should fail.");
+
+    assert!(
+        !late_file.exists(),
+        "The process started by the command should be killed along with it."
+    );
+
+    assert_eq!(
+        fs::read_to_string(&readme)?,
+        "An example README.\nA new line.\n",
+        "The working directory should be restored."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn merge_status_is_restored_when_backup_stash_does_not_match() -> Result<()> {
+    // Given
+    let working_dir = initialize("merge_status_is_restored_when_backup_stash_does_not_match")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    repository.start_merge()?;
+    let merge_head = fs::read_to_string(repository.get_git_dir().join("MERGE_HEAD"))?;
+
+    // When
+    // The command moves HEAD to a commit with another file, which the backup
+    // stash applies on top of cleanly but does not have.
+    let output = cmd!(
+        BINARY_NAME,
+        "echo 'A note.' > notes.txt && git add notes.txt && \
+         git update-ref HEAD $(git -c user.name=Test -c user.email=test@example.com \
+         commit-tree $(git write-tree) -p HEAD -m 'Commit a note.') && exit 1 #"
+    )
+    .dir(&working_dir)
+    .stderr_capture()
+    .unchecked()
+    .run()?;
+
+    // Then
+    assert!(!output.status.success(), "The command should fail.");
+
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(
+        stderr.contains("do not match backup stash"),
+        "The mismatch with the backup stash should be reported, but was: {}",
+        stderr
+    );
+
+    assert_eq!(
+        fs::read_to_string(repository.get_git_dir().join("MERGE_HEAD"))?,
+        merge_head,
+        "The merge status should be restored anyway."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn dirty_submodule_does_not_keep_backup_stash() -> Result<()> {
+    // Given
+    let working_dir = initialize("dirty_submodule_does_not_keep_backup_stash")?;
+    let submodule_source_dir = initialize("dirty_submodule_does_not_keep_backup_stash_source")?;
+
+    let mut submodule_source = TestRepository::new(&submodule_source_dir)?;
+    submodule_source.initial_commit()?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    cmd!(
+        "git",
+        "-c",
+        "protocol.file.allow=always",
+        "submodule",
+        "--quiet",
+        "add",
+        &submodule_source_dir,
+        "sub"
+    )
+    .dir(&working_dir)
+    .run()?;
+
+    cmd!(
+        "git",
+        "-c",
+        "user.name=me",
+        "-c",
+        "user.email=me@example.com",
+        "commit",
+        "--quiet",
+        "-m",
+        "Add a submodule."
+    )
+    .dir(&working_dir)
+    .run()?;
+
+    let submodule_readme = working_dir.join("sub").join(README);
+    append_line(&submodule_readme, "A change in the submodule.")?;
+
+    // The index changed behind the back of the test repository, so the file
+    // is staged with Git as well.
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    cmd!("git", "add", README).dir(&working_dir).run()?;
+
+    // When
+    let output = cmd!(BINARY_NAME, "exit 1 #")
+        .dir(&working_dir)
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+
+    // Then
+    assert_eq!(output.status.code(), Some(1), "The command should fail.");
+
+    let stderr = String::from_utf8(output.stderr)?;
+
+    assert!(
+        !stderr.contains("do not match backup stash"),
+        "The dirty submodule should not count as a mismatch, but was: {}",
+        stderr
+    );
+
+    assert_eq!(
+        repository.count_stashes()?,
+        0,
+        "The backup stash should be dropped after restoring from it."
+    );
+
+    assert_eq!(
+        fs::read_to_string(&submodule_readme)?,
+        "An example README.\nA change in the submodule.\n",
+        "The change in the submodule should be left alone."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn worktree_keeps_merge_status_after_command_fails() -> Result<()> {
+    // Given
+    let working_dir = initialize("worktree_keeps_merge_status_after_command_fails")?;
+
+    let worktree_dir = initialize("worktree_keeps_merge_status_after_command_fails_worktree")?;
+    fs::remove_dir(&worktree_dir)?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let mut worktree = repository.add_worktree("linked", &worktree_dir)?;
+
+    let readme = worktree_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    worktree.stage_path(&readme)?;
+
+    worktree.start_merge()?;
+    let merge_head = fs::read_to_string(worktree.get_git_dir().join("MERGE_HEAD"))?;
+
+    // When
+    let output = cmd!(BINARY_NAME, "exit 1")
+        .dir(&worktree_dir)
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+
+    // Then
+    assert!(!output.status.success(), "The command should fail.");
+
+    assert_eq!(
+        fs::read_to_string(worktree.get_git_dir().join("MERGE_HEAD"))?,
+        merge_head,
+        "The merge status of the worktree should be restored."
+    );
+
+    assert!(
+        !repository.get_git_dir().join("MERGE_HEAD").exists(),
+        "The merge status should not leak into the main repository."
+    );
+
+    assert_eq!(
+        fs::read_to_string(&readme)?,
+        "An example README.\nA new line.\n",
+        "The working directory of the worktree should be restored."
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn unstaged_mode_change_survives_snapshot() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Given
+    let working_dir = initialize("unstaged_mode_change_survives_snapshot")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    fs::set_permissions(&readme, fs::Permissions::from_mode(0o755))?;
+    append_line(&readme, "An unstaged line.")?;
+
+    // When
+    cmd!(BINARY_NAME, "true").dir(&working_dir).run()?;
+
+    // Then
+    assert_eq!(
+        fs::metadata(&readme)?.permissions().mode() & 0o777,
+        0o755,
+        "The unstaged change to the mode of the file should be restored."
+    );
+
+    assert_eq!(
+        fs::read_to_string(&readme)?,
+        "An example README.\nA new line.\nAn unstaged line.\n",
+        "The unstaged change to the contents of the file should be restored."
+    );
+
+    let staged_mode = cmd!("git", "ls-files", "--stage", README)
+        .dir(&working_dir)
+        .read()?;
+
+    assert!(
+        staged_mode.starts_with("100644 "),
+        "The change to the mode of the file should stay unstaged."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn progress_does_not_leak_into_piped_output() -> Result<()> {
+    // Given
+    let working_dir = initialize("progress_does_not_leak_into_piped_output")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let output = cmd!(BINARY_NAME, "--progress", "echo")
+        .dir(&working_dir)
+        .stdout_capture()
+        .stderr_capture()
+        .run()?;
+
+    // Then
+    assert_eq!(
+        String::from_utf8(output.stdout)?,
+        format!("{}\n", README),
+        "Only the output of the command should be on standard output."
+    );
+
+    assert!(
+        !output.stderr.contains(&b'\x1b'),
+        "No escape codes should be written when standard error is piped."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn cwd_relative_filters_inside_current_directory() -> Result<()> {
+    // Given
+    let working_dir = initialize("cwd_relative_filters_inside_current_directory")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let nested_dir = working_dir.join("nested");
+    fs::create_dir(&nested_dir)?;
+
+    let nested_notes = repository.create_file("nested/notes.txt", "A nested note.")?;
+    repository.stage_path(&nested_notes)?;
+
+    let notes = repository.create_file("notes.txt", "A note.")?;
+    repository.stage_path(&notes)?;
+
+    // When
+    let stdout = cmd!(
+        BINARY_NAME,
+        "--cwd-relative",
+        "--filter",
+        "notes.txt",
+        "echo {} && cat {}"
+    )
+    .dir(&nested_dir)
+    .read()?;
+
+    // Then
+    assert_eq!(
+        stdout, "notes.txt\nA nested note.",
+        "Only the staged file inside the current directory should be passed, relative to it."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn install_hook_writes_pre_commit_hook() -> Result<()> {
+    // Given
+    let working_dir = initialize("install_hook_writes_pre_commit_hook")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    // When
+    cmd!(
+        BINARY_NAME,
+        "--filter",
+        "*.js",
+        "--command",
+        "prettier --write",
+        "install-hook"
+    )
+    .dir(&working_dir)
+    .stderr_null()
+    .run()?;
+
+    // Then
+    let hook = repository.get_git_dir().join("hooks/pre-commit");
+
+    assert_eq!(
+        fs::read_to_string(&hook)?,
+        "#!/bin/sh\n\n# Installed by offstage.\n\
+         offstage --filter '*.js' --command 'prettier --write' || exit $?\n"
+    );
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        assert_ne!(
+            fs::metadata(&hook)?.permissions().mode() & 0o111,
+            0,
+            "The hook should be executable."
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn install_hook_again_replaces_its_line() -> Result<()> {
+    // Given
+    let working_dir = initialize("install_hook_again_replaces_its_line")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    cmd!(BINARY_NAME, "--command", "eslint", "install-hook")
+        .dir(&working_dir)
+        .stderr_null()
+        .run()?;
+
+    // When
+    for _ in 0..2 {
+        cmd!(BINARY_NAME, "--command", "prettier --write", "install-hook")
+            .dir(&working_dir)
+            .stderr_null()
+            .run()?;
+    }
+
+    // Then
+    let hook = repository.get_git_dir().join("hooks/pre-commit");
+
+    assert_eq!(
+        fs::read_to_string(&hook)?,
+        "#!/bin/sh\n\n# Installed by offstage.\n\
+         offstage --command 'prettier --write' || exit $?\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn install_hook_leaves_foreign_hook_unless_forced() -> Result<()> {
+    // Given
+    let working_dir = initialize("install_hook_leaves_foreign_hook_unless_forced")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let hook = repository.get_git_dir().join("hooks/pre-commit");
+    fs::create_dir_all(repository.get_git_dir().join("hooks"))?;
+    fs::write(&hook, "#!/bin/sh\necho foreign\n")?;
+
+    // When
+    let output = cmd!(BINARY_NAME, "--command", "eslint", "install-hook")
+        .dir(&working_dir)
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+
+    // Then
+    assert!(!output.status.success(), "Installing should fail.");
+
+    assert_eq!(
+        fs::read_to_string(&hook)?,
+        "#!/bin/sh\necho foreign\n",
+        "The existing hook should be left alone."
+    );
+
+    // When
+    cmd!(
+        BINARY_NAME,
+        "--command",
+        "eslint",
+        "install-hook",
+        "--force"
+    )
+    .dir(&working_dir)
+    .stderr_null()
+    .run()?;
+
+    // Then
+    assert_eq!(
+        fs::read_to_string(&hook)?,
+        "#!/bin/sh\necho foreign\n\n# Installed by offstage.\n\
+         offstage --command 'eslint' || exit $?\n",
+        "Offstage should be added to the end of the existing hook."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn install_hook_passes_options_along() -> Result<()> {
+    // Given
+    let working_dir = initialize("install_hook_passes_options_along")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    // When
+    cmd!(
+        BINARY_NAME,
+        "--filter",
+        "*.js",
+        "--config",
+        "offstage.toml",
+        "--ignore-case",
+        "--diff-filter",
+        "AM",
+        "--stdin",
+        "--shell",
+        "bash",
+        "--timeout",
+        "30",
+        "--no-hide-partial",
+        "--jobs",
+        "2",
+        "install-hook"
+    )
+    .dir(&working_dir)
+    .env("SHELL", "sh")
+    .stderr_null()
+    .run()?;
+
+    // Then
+    let hook = repository.get_git_dir().join("hooks/pre-commit");
+    let config = working_dir.canonicalize()?.join("offstage.toml");
+
+    assert_eq!(
+        fs::read_to_string(&hook)?,
+        format!(
+            "#!/bin/sh\n\n# Installed by offstage.\n\
+             offstage --filter '*.js' --config '{}' --stdin --diff-filter 'AM' \
+             --shell 'bash' --ignore-case --timeout 30 --no-hide-partial --jobs 2 \
+             || exit $?\n",
+            config.display()
+        ),
+        "The hook should run offstage with the same options."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn install_hook_refuses_gc_stashes() -> Result<()> {
+    // Given
+    let working_dir = initialize("install_hook_refuses_gc_stashes")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    // When
+    let output = cmd!(
+        BINARY_NAME,
+        "--gc-stashes",
+        "--command",
+        "eslint",
+        "install-hook"
+    )
+    .dir(&working_dir)
+    .stderr_capture()
+    .unchecked()
+    .run()?;
+
+    // Then
+    assert!(!output.status.success(), "Installing should fail.");
+
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(
+        stderr.contains("Leave out --gc-stashes."),
+        "The error should name the option, but was: {}",
+        stderr
+    );
+
+    Ok(())
+}
+
+#[test]
+fn install_hook_refuses_cwd_relative() -> Result<()> {
+    // Given
+    let working_dir = initialize("install_hook_refuses_cwd_relative")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    // When
+    let output = cmd!(
+        BINARY_NAME,
+        "--cwd-relative",
+        "--command",
+        "eslint",
+        "install-hook"
+    )
+    .dir(&working_dir)
+    .stderr_capture()
+    .unchecked()
+    .run()?;
+
+    // Then
+    assert!(!output.status.success(), "Installing should fail.");
+
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(
+        stderr.contains("Leave out --cwd-relative."),
+        "The error should name the option, but was: {}",
+        stderr
+    );
+
+    assert!(
+        !repository.get_git_dir().join("hooks/pre-commit").exists(),
+        "No hook should be installed."
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn skip_symlinks_leaves_staged_symlink_out() -> Result<()> {
+    // Given
+    let working_dir = initialize("skip_symlinks_leaves_staged_symlink_out")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    let link = working_dir.join("LINK");
+    std::os::unix::fs::symlink(README, &link)?;
+    repository.stage_path(&link)?;
+
+    // When
+    let stdout = cmd!(
+        BINARY_NAME,
+        "--skip-symlinks",
+        "printf 'Formatted.\\n' | tee -a"
+    )
+    .dir(&working_dir)
+    .read()?;
+
+    // Then
+    assert_eq!(
+        stdout, "Formatted.",
+        "The command should only run against the regular file."
+    );
+
+    assert!(
+        fs::symlink_metadata(&link)?.file_type().is_symlink(),
+        "The staged symlink should still be a symlink."
+    );
+
+    assert_eq!(
+        fs::read_to_string(&readme)?,
+        "An example README.\nA new line.\nFormatted.\n",
+        "The target of the symlink should only be modified once."
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn staged_symlink_stays_a_link_through_the_snapshot() -> Result<()> {
+    // Given
+    let working_dir = initialize("staged_symlink_stays_a_link_through_the_snapshot")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let link = working_dir.join("LINK");
+    std::os::unix::fs::symlink(README, &link)?;
+    repository.stage_path(&link)?;
+    repository.commit("Add a link.")?;
+
+    // The link is partially staged, pointing somewhere else in the working
+    // directory than in the index, so that it is hidden and brought back.
+    fs::remove_file(&link)?;
+    std::os::unix::fs::symlink(LICENSE, &link)?;
+    repository.stage_path(&link)?;
+    fs::remove_file(&link)?;
+    std::os::unix::fs::symlink("missing", &link)?;
+
+    // When
+    let success_stdout = cmd!(BINARY_NAME, "readlink").dir(&working_dir).read()?;
+
+    let failure_output = cmd!(BINARY_NAME, "readlink {} && exit 1")
+        .dir(&working_dir)
+        .stdout_capture()
+        .stderr_null()
+        .unchecked()
+        .run()?;
+
+    // Then
+    assert_eq!(
+        success_stdout, LICENSE,
+        "The command should see the staged link."
+    );
+
+    assert!(!failure_output.status.success(), "The command should fail.");
+
+    assert!(
+        fs::symlink_metadata(&link)?.file_type().is_symlink(),
+        "The staged symlink should still be a symlink."
+    );
+
+    assert_eq!(
+        fs::read_link(&link)?,
+        PathBuf::from("missing"),
+        "The unstaged change to the link should be restored."
+    );
+
+    assert_eq!(
+        fs::read_to_string(working_dir.join(README))?,
+        "An example README.\n",
+        "The former target of the link should be left alone."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn staged_files_are_passed_sorted() -> Result<()> {
+    // Given
+    let working_dir = initialize("staged_files_are_passed_sorted")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    // Git orders paths byte by byte, which puts a-b.txt before a/b.txt, while
+    // sorting by path compares the directory a first.
+    fs::create_dir_all(working_dir.join("a"))?;
+
+    for name in &["c.txt", "a/b.txt", "a-b.txt"] {
+        let path = repository.create_file(name, "A file.")?;
+        repository.stage_path(&path)?;
+    }
+
+    // When
+    let first_stdout = cmd!(BINARY_NAME, "echo").dir(&working_dir).read()?;
+    let second_stdout = cmd!(BINARY_NAME, "echo").dir(&working_dir).read()?;
+
+    // Then
+    assert_eq!(
+        first_stdout, "a/b.txt a-b.txt c.txt",
+        "The staged files should be sorted by path."
+    );
+
+    assert_eq!(
+        first_stdout, second_stdout,
+        "The staged files should be passed in the same order every time."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn no_sort_passes_staged_files_in_git_order() -> Result<()> {
+    // Given
+    let working_dir = initialize("no_sort_passes_staged_files_in_git_order")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    fs::create_dir_all(working_dir.join("a"))?;
+
+    for name in &["c.txt", "a/b.txt", "a-b.txt"] {
+        let path = repository.create_file(name, "A file.")?;
+        repository.stage_path(&path)?;
+    }
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "--no-sort", "echo")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert_eq!(
+        stdout, "a-b.txt a/b.txt c.txt",
+        "The staged files should be passed in the order Git lists them."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn missing_repository_reports_clear_error() -> Result<()> {
+    // Given
+    let plain_dir = env::temp_dir().join("offstage_missing_repository_reports_clear_error");
+    if plain_dir.is_dir() {
+        fs::remove_dir_all(&plain_dir)?;
+    }
+    fs::create_dir(&plain_dir)?;
+
+    // When
+    let output = cmd!(BINARY_NAME, "echo")
+        .dir(&plain_dir)
+        .env_remove("GIT_DIR")
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+
+    // Then
+    assert_eq!(
+        output.status.code(),
+        Some(128),
+        "Offstage should exit with the code for a missing repository."
+    );
+
+    let stderr = String::from_utf8(output.stderr)?;
+
+    assert!(
+        stderr.contains("No Git repository was found"),
+        "Error \"{}\" should say that no repository was found.",
+        stderr
+    );
+
+    Ok(())
+}
+
+#[test]
+fn failed_restore_keeps_backup_stash() -> Result<()> {
+    // Given
+    let working_dir = initialize("failed_restore_keeps_backup_stash")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    // The command commits a conflicting change, so that the backup stash cannot
+    // be applied cleanly on top of the new HEAD commit.
+    let output = cmd!(
+        BINARY_NAME,
+        format!(
+            "printf 'A conflicting line.\\n' > {0}; git add {0}; \
+             git -c user.name=me -c user.email=me@example.com commit -q -m Conflict; \
+             exit 1",
+            README
+        )
+    )
+    .dir(&working_dir)
+    .stderr_capture()
+    .unchecked()
+    .run()?;
+
+    // Then
+    assert!(!output.status.success(), "The command should fail.");
+
+    let stderr = String::from_utf8(output.stderr)?;
+
+    assert!(
+        stderr.contains("restoring snapshot"),
+        "Error \"{}\" should contain the restore failure.",
+        stderr
+    );
+
+    assert_eq!(
+        repository.count_stashes()?,
+        1,
+        "The backup stash should have been kept."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn git_config_provides_default_command() -> Result<()> {
+    // Given
+    let working_dir = initialize("git_config_provides_default_command")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    cmd!("git", "config", "offstage.command", "echo configured")
+        .dir(&working_dir)
+        .run()?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME).dir(&working_dir).read()?;
+
+    // Then
+    assert_eq!(
+        stdout,
+        format!("configured {}", README),
+        "The command in the Git configuration should run."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn git_config_command_is_overridden_by_config_flag() -> Result<()> {
+    // Given
+    let working_dir = initialize("git_config_command_is_overridden_by_config_flag")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    repository.create_file("other.toml", r#""README" = "echo from file""#)?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    cmd!("git", "config", "offstage.command", "echo configured")
+        .dir(&working_dir)
+        .run()?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "--config", "other.toml")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert_eq!(
+        stdout,
+        format!("from file {}", README),
+        "The configuration file given on the command line should be used."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn git_config_filter_is_overridden_by_flag() -> Result<()> {
+    // Given
+    let working_dir = initialize("git_config_filter_is_overridden_by_flag")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+
+    cmd!("git", "config", "offstage.filter", LICENSE)
+        .dir(&working_dir)
+        .run()?;
+
+    // When
+    let configured_stdout = cmd!(BINARY_NAME, "echo").dir(&working_dir).read()?;
+
+    let overridden_stdout = cmd!(BINARY_NAME, "--filter", README, "echo")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert_eq!(
+        configured_stdout, LICENSE,
+        "The filter in the Git configuration should be used without the flag."
+    );
+
+    assert_eq!(
+        overridden_stdout, README,
+        "The filter given on the command line should be used instead."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn no_hide_partial_shows_unstaged_changes() -> Result<()> {
+    // Given
+    let working_dir = initialize("no_hide_partial_shows_unstaged_changes")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A staged line.")?;
+    repository.stage_path(&readme)?;
+    append_line(&readme, "An unstaged line.")?;
+
+    // When
+    let hidden_stdout = cmd!(BINARY_NAME, "cat").dir(&working_dir).read()?;
+
+    let shown_stdout = cmd!(BINARY_NAME, "--no-hide-partial", "cat")
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert_eq!(
+        hidden_stdout, "An example README.\nA staged line.",
+        "The command should only see staged content by default."
+    );
+
+    assert_eq!(
+        shown_stdout, "An example README.\nA staged line.\nAn unstaged line.",
+        "The command should see the unstaged changes with the flag."
+    );
+
+    repository.commit("Add a staged line.")?;
+
+    assert_eq!(
+        repository.read_committed_file(README)?,
+        Some("An example README.\nA staged line.\n".to_string()),
+        "The unstaged changes should stay unstaged when the command does not modify the file."
+    );
+
+    assert_eq!(
+        fs::read_to_string(&readme)?,
+        "An example README.\nA staged line.\nAn unstaged line.\n",
+        "The unstaged changes should remain in the working directory."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn no_hide_partial_stages_unstaged_changes_of_modified_files() -> Result<()> {
+    // Given
+    let working_dir = initialize("no_hide_partial_stages_unstaged_changes_of_modified_files")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A staged line.")?;
+    repository.stage_path(&readme)?;
+    append_line(&readme, "An unstaged line.")?;
+
+    // When
+    cmd!(
+        BINARY_NAME,
+        "--no-hide-partial",
+        "printf 'A formatted line.\\n' >>"
+    )
+    .dir(&working_dir)
+    .run()?;
+
+    repository.commit("Format the README.")?;
+
+    // Then
+    assert_eq!(
+        repository.read_committed_file(README)?,
+        Some(
+            "An example README.\nA staged line.\nAn unstaged line.\nA formatted line.\n"
+                .to_string()
+        ),
+        "The unstaged changes should be staged along with the modification."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn jobs_run_command_groups_in_parallel() -> Result<()> {
+    // Given
+    let working_dir = initialize("jobs_run_command_groups_in_parallel")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    repository.create_file(
+        CONFIG,
+        r#"
+"*.md" = ["echo markdown", "printf 'Formatted.\\n' >>"]
+"LICENSE" = ["echo license", "printf 'Formatted.\\n' >>"]
+"#,
+    )?;
+
+    let notes = repository.create_file("notes.md", "Some notes.")?;
+    repository.stage_path(&notes)?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "--jobs", "2").dir(&working_dir).read()?;
+
+    // Then
+    assert!(
+        stdout.contains("markdown notes.md"),
+        "Output \"{}\" should contain the markdown command.",
+        stdout
+    );
+
+    assert!(
+        stdout.contains("license LICENSE"),
+        "Output \"{}\" should contain the license command.",
+        stdout
+    );
+
+    assert_eq!(
+        fs::read_to_string(&notes)?,
+        "Some notes.\nFormatted.\n",
+        "The markdown file should be modified."
+    );
+
+    assert_eq!(
+        fs::read_to_string(&license)?,
+        "Free as in freedom.\nFormatted.\n",
+        "The license should be modified."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn jobs_run_overlapping_command_groups_in_order() -> Result<()> {
+    // Given
+    let working_dir = initialize("jobs_run_overlapping_command_groups_in_order")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    repository.create_file(
+        CONFIG,
+        r#"
+"*.md" = "sleep 1; printf 'First.\\n' >>"
+"notes*" = "printf 'Second.\\n' >>"
+"LICENSE" = "printf 'Formatted.\\n' >>"
+"#,
+    )?;
+
+    let notes = repository.create_file("notes.md", "Some notes.")?;
+    repository.stage_path(&notes)?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+
+    // When
+    cmd!(BINARY_NAME, "--jobs", "3")
+        .dir(&working_dir)
+        .stdout_null()
+        .run()?;
+
+    // Then
+    assert_eq!(
+        fs::read_to_string(&notes)?,
+        "Some notes.\nFirst.\nSecond.\n",
+        "The groups matching the same file should run one after another."
+    );
+
+    assert_eq!(
+        fs::read_to_string(&license)?,
+        "Free as in freedom.\nFormatted.\n",
+        "The other group should still run."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn jobs_fail_when_any_command_group_fails() -> Result<()> {
+    // Given
+    let working_dir = initialize("jobs_fail_when_any_command_group_fails")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    repository.create_file(
+        CONFIG,
+        r#"
+"*.md" = "echo markdown"
+"LICENSE" = "false"
+"#,
+    )?;
+
+    let notes = repository.create_file("notes.md", "Some notes.")?;
+    repository.stage_path(&notes)?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+
+    // When
+    let output = cmd!(BINARY_NAME, "--jobs", "2")
+        .dir(&working_dir)
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+
+    // Then
+    assert!(!output.status.success(), "The command should fail.");
+
+    let stdout = String::from_utf8(output.stdout)?;
+
+    assert!(
+        stdout.contains("markdown notes.md"),
+        "Output \"{}\" should show the other group still ran.",
+        stdout
+    );
+
+    Ok(())
+}
+
+#[test]
+fn jobs_exit_with_code_of_failed_command_group() -> Result<()> {
+    // Given
+    let working_dir = initialize("jobs_exit_with_code_of_failed_command_group")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    repository.create_file(
+        CONFIG,
+        r#"
+"*.md" = "exit 3 #"
+"LICENSE" = "exit 3 #"
+"#,
+    )?;
+
+    let notes = repository.create_file("notes.md", "Some notes.")?;
+    repository.stage_path(&notes)?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+
+    // When
+    let output = cmd!(BINARY_NAME, "--jobs", "2", "--json")
+        .dir(&working_dir)
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+
+    // Then
+    assert_eq!(
+        output.status.code(),
+        Some(3),
+        "Offstage should exit with the code of the failed commands."
+    );
+
+    let summary: Value = serde_json::from_slice(&output.stdout)?;
+
+    assert_eq!(
+        summary["exit_code"],
+        json!(3),
+        "The summary should have the exit code of the failed commands."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn rebase_in_progress_is_refused() -> Result<()> {
+    // Given
+    let working_dir = initialize("rebase_in_progress_is_refused")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A staged line.")?;
+    repository.stage_path(&readme)?;
+    append_line(&readme, "An unstaged line.")?;
+
+    repository.start_rebase()?;
+
+    // When
+    let output = cmd!(BINARY_NAME, "echo")
+        .dir(&working_dir)
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+
+    // Then
+    assert!(!output.status.success(), "The command should fail.");
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let stderr = String::from_utf8(output.stderr)?;
+
+    assert!(stdout.is_empty(), "The command should not run.");
+
+    assert!(
+        stderr.contains("rebase is in progress"),
+        "Error \"{}\" should mention the rebase.",
+        stderr
+    );
+
+    assert_eq!(
+        repository.count_stashes()?,
+        0,
+        "No backup stash should be created."
+    );
+
+    assert_eq!(
+        fs::read_to_string(&readme)?,
+        "An example README.\nA staged line.\nAn unstaged line.\n",
+        "The working directory should be untouched."
+    );
+
+    assert!(
+        repository.get_git_dir().join("rebase-merge").exists(),
+        "The rebase should still be in progress."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn flags_after_separator_are_passed_to_command() -> Result<()> {
+    // Given
+    let working_dir = initialize("flags_after_separator_are_passed_to_command")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let stdout = cmd!(
+        BINARY_NAME,
+        "--filter",
+        README,
+        "--",
+        "echo",
+        "--filter",
+        "--version"
+    )
+    .dir(&working_dir)
+    .read()?;
+
+    // Then
+    assert_eq!(
+        stdout,
+        format!("--filter --version {}", README),
+        "The flags after the separator should be passed to the command."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn flags_after_command_are_passed_to_command() -> Result<()> {
+    // Given
+    let working_dir = initialize("flags_after_command_are_passed_to_command")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "--filter", README, "echo", "--exclude", README)
+        .dir(&working_dir)
+        .read()?;
+
+    // Then
+    assert_eq!(
+        stdout,
+        format!("--exclude {} {}", README, README),
+        "The flags after the command should be passed to it instead of excluding files."
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn separator_runs_command_named_like_subcommand() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Given
+    let working_dir = initialize("separator_runs_command_named_like_subcommand")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let bin_dir = working_dir.join("bin");
+    fs::create_dir(&bin_dir)?;
+
+    let script = bin_dir.join("list-files");
+    fs::write(&script, "#!/bin/sh\necho running \"$@\"\n")?;
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755))?;
+
+    let path = format!("{}:{}", bin_dir.display(), env::var("PATH")?);
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let stdout = cmd!(BINARY_NAME, "--filter", README, "--", "list-files")
+        .dir(&working_dir)
+        .env("PATH", path)
+        .read()?;
+
+    // Then
+    assert_eq!(
+        stdout,
+        format!("running {}", README),
+        "The command should run instead of the subcommand."
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn non_utf8_path_is_passed_on_stdin_or_reported() -> Result<()> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    // Given
+    let working_dir = initialize("non_utf8_path_is_passed_on_stdin_or_reported")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let path = working_dir.join(OsStr::from_bytes(b"caf\xe9.txt"));
+    fs::write(&path, "Some content.\n")?;
+    repository.stage_path(&path)?;
+
+    // When
+    let stdin_stdout = cmd!(BINARY_NAME, "--stdin0", "xargs", "-0", "cat")
+        .dir(&working_dir)
+        .read()?;
+
+    let arguments_output = cmd!(BINARY_NAME, "cat")
+        .dir(&working_dir)
+        .stdout_capture()
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+
+    let dry_run_output = cmd!(BINARY_NAME, "--dry-run", "cat")
+        .dir(&working_dir)
+        .stdout_null()
+        .stderr_capture()
+        .unchecked()
+        .run()?;
+
+    // Then
+    assert_eq!(
+        stdin_stdout, "Some content.",
+        "The file should be passed on standard input."
+    );
+
+    assert!(
+        !arguments_output.status.success(),
+        "The command should fail rather than leave the file out."
+    );
+
+    assert!(
+        arguments_output.stdout.is_empty(),
+        "The command should not run."
+    );
+
+    let stderr = String::from_utf8_lossy(&arguments_output.stderr);
+
+    assert!(
+        stderr.contains("does not have a UTF-8 path"),
+        "Error \"{}\" should explain the file was not passed.",
+        stderr
+    );
+
+    let dry_run_stderr = String::from_utf8_lossy(&dry_run_output.stderr);
+
+    assert!(
+        !dry_run_output.status.success() && dry_run_stderr.contains("does not have a UTF-8 path"),
+        "A dry run should fail the same way, but reported \"{}\".",
+        dry_run_stderr
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn json_summary_lists_non_utf8_path() -> Result<()> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    // Given
+    let working_dir = initialize("json_summary_lists_non_utf8_path")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let path = working_dir.join(OsStr::from_bytes(b"caf\xe9.txt"));
+    fs::write(&path, "Some content.\n")?;
+    repository.stage_path(&path)?;
+
+    // When
+    let output = cmd!(BINARY_NAME, "--json", "--stdin0", "xargs", "-0", "cat")
+        .dir(&working_dir)
+        .stdout_capture()
+        .stderr_null()
+        .unchecked()
+        .run()?;
+
+    // Then
+    assert!(output.status.success(), "The run should succeed.");
+
+    let summary: Value = serde_json::from_slice(&output.stdout)?;
+
+    assert_eq!(
+        summary["staged_files"],
+        json!(["caf\u{fffd}.txt"]),
+        "The path should be listed with what is not UTF-8 replaced."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn modification_to_staged_file_is_committed() -> Result<()> {
+    // Given
+    let working_dir = initialize("modification_to_staged_file_is_committed")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A staged line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    cmd!(BINARY_NAME, "printf 'A formatted line.\\n' >>")
+        .dir(&working_dir)
+        .run()?;
+
+    repository.commit("Format the README.")?;
+
+    // Then
+    assert_eq!(
+        repository.read_committed_file(README)?,
+        Some("An example README.\nA staged line.\nA formatted line.\n".to_string()),
+        "The modification should be committed."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn amend_index_stages_other_files_the_command_changed() -> Result<()> {
+    // Given
+    let working_dir = initialize("amend_index_stages_other_files_the_command_changed")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let license = repository.create_license()?;
+    repository.stage_path(&license)?;
+    repository.commit("Add a license.")?;
+
+    let notes = repository.create_file("notes.md", "Some notes.")?;
+    repository.stage_path(&notes)?;
+    repository.commit("Add notes.")?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A staged line.")?;
+    repository.stage_path(&readme)?;
+
+    append_line(&notes, "An unstaged line.")?;
+
+    let command = "printf 'Changed.\\n' | tee -a LICENSE notes.md >/dev/null; \
+                   printf 'Created.\\n' > NEW; true";
+
+    // When
+    cmd!(BINARY_NAME, "--amend-index", command)
+        .dir(&working_dir)
+        .run()?;
+
+    repository.commit("Change the README.")?;
+
+    // Then
+    assert_eq!(
+        repository.read_committed_file(LICENSE)?,
+        Some("Free as in freedom.\nChanged.\n".to_string()),
+        "The change to the clean file should be committed."
+    );
+
+    assert_eq!(
+        repository.read_committed_file("NEW")?,
+        Some("Created.\n".to_string()),
+        "The created file should be committed."
+    );
+
+    assert_eq!(
+        repository.read_committed_file("notes.md")?,
+        Some("Some notes.\n".to_string()),
+        "The file with unstaged changes should not be committed."
+    );
+
+    assert_eq!(
+        fs::read_to_string(&notes)?,
+        "Some notes.\nAn unstaged line.\nChanged.\n",
+        "The file with unstaged changes should keep the change unstaged."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn leftover_backup_stash_is_reported() -> Result<()> {
+    // Given
+    let working_dir = initialize("leftover_backup_stash_is_reported")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A stashed line.")?;
+    let stash_id = store_stash(&working_dir, "offstage backup")?;
+
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let output = cmd!(BINARY_NAME, "true")
+        .dir(&working_dir)
+        .stderr_capture()
+        .run()?;
+
+    // Then
+    let stderr = String::from_utf8(output.stderr)?;
+
+    assert!(
+        stderr.contains(&stash_id),
+        "Error output \"{}\" should contain the stash id {}.",
+        stderr,
+        stash_id
+    );
+
+    assert_eq!(
+        repository.count_stashes()?,
+        1,
+        "The backup stash should be kept."
+    );
+
+    Ok(())
+}
+
+#[test]
+fn gc_stashes_drops_only_backup_stashes() -> Result<()> {
+    // Given
+    let working_dir = initialize("gc_stashes_drops_only_backup_stashes")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A backed up line.")?;
+    store_stash(&working_dir, "offstage backup")?;
+    append_line(&readme, "A line of my own.")?;
+    let own_stash_id = store_stash(&working_dir, "my own work")?;
+    append_line(&readme, "Another backed up line.")?;
+    store_stash(&working_dir, "offstage backup")?;
+
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    cmd!(BINARY_NAME, "--gc-stashes", "true")
+        .dir(&working_dir)
+        .stderr_null()
+        .run()?;
+
+    // Then
+    assert_eq!(
+        repository.count_stashes()?,
+        1,
+        "Only the backup stashes should be dropped."
+    );
+
+    let remaining_stash_id = cmd!("git", "rev-parse", "stash@{0}")
+        .dir(&working_dir)
+        .read()?;
+
+    assert_eq!(
+        remaining_stash_id, own_stash_id,
+        "The other stash should be kept."
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn gc_stashes_keeps_backup_stashes_of_running_processes() -> Result<()> {
+    // Given
+    let working_dir = initialize("gc_stashes_keeps_backup_stashes_of_running_processes")?;
+
+    let mut repository = TestRepository::new(&working_dir)?;
+
+    repository.initial_commit()?;
+
+    // The test itself is a running process, while a process which has been
+    // waited for is gone.
+    let running_pid = std::process::id();
+
+    let mut finished_process = std::process::Command::new("true").spawn()?;
+    let finished_pid = finished_process.id();
+    finished_process.wait()?;
+
+    let readme = working_dir.join(README);
+    append_line(&readme, "A line of a running backup.")?;
+    let running_stash_id = store_stash(
+        &working_dir,
+        &format!("offstage backup (pid {})", running_pid),
+    )?;
+    append_line(&readme, "A line of a finished backup.")?;
+    let finished_stash_id = store_stash(
+        &working_dir,
+        &format!("offstage backup (pid {})", finished_pid),
+    )?;
+
+    append_line(&readme, "A new line.")?;
+    repository.stage_path(&readme)?;
+
+    // When
+    let output = cmd!(BINARY_NAME, "--gc-stashes", "true")
+        .dir(&working_dir)
+        .stderr_capture()
+        .run()?;
+
+    // Then
+    let stderr = String::from_utf8(output.stderr)?;
+
+    assert!(
+        stderr.contains(&finished_stash_id) && !stderr.contains(&running_stash_id),
+        "Error output \"{}\" should only name the stash of the finished process.",
+        stderr
+    );
+
+    let remaining_stash_id = cmd!("git", "rev-parse", "stash@{0}")
+        .dir(&working_dir)
+        .read()?;
+
+    assert_eq!(
+        remaining_stash_id, running_stash_id,
+        "The backup stash of the running process should be kept."
+    );
+
+    assert_eq!(
+        repository.count_stashes()?,
+        1,
+        "Only one stash should be left."
+    );
+
+    Ok(())
+}
+
+fn store_stash<P: AsRef<Path>>(working_dir: P, message: &str) -> Result<String> {
+    let stash_id = cmd!(
+        "git",
+        "-c",
+        "user.name=me",
+        "-c",
+        "user.email=me@example.com",
+        "stash",
+        "create"
+    )
+    .dir(working_dir.as_ref())
+    .read()?;
+
+    cmd!("git", "stash", "store", "-m", message, &stash_id)
+        .dir(working_dir.as_ref())
+        .run()?;
+
+    Ok(stash_id)
+}
+
 fn append_line<P: AsRef<Path>>(path: P, line: &str) -> Result<()> {
     let mut file = OpenOptions::new().append(true).open(path.as_ref())?;
 